@@ -0,0 +1,187 @@
+extern crate bitcask;
+
+use std::env;
+use std::io;
+use std::io::Write;
+use std::io::BufReader;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process;
+
+use bitcask::Bitcask;
+use bitcask::BitcaskOptions;
+use bitcask::ExportFormat;
+
+
+const USAGE: &'static str = "usage: bitcask-tool [--key-file FILE] [--format json|binary] [--batch N] COMMAND
+commands:
+  dump FILE        print the records of a data or hint file
+  verify [DIR]     check checksums and that hint files match data files
+  repair [DIR]     cut torn tails and corrupt records, rewrite hint files
+  stats [DIR]      live and dead records per data file
+  get KEY [DIR]    print the value of KEY
+  list-keys [DIR]  print every live key
+  export [DIR]     write every key and value to stdout
+  import [DIR]     put every key and value read from stdin";
+
+
+fn main() {
+    let mut key_file = None;
+    let mut format = ExportFormat::Json;
+    let mut batch_size = 1000;
+    let mut args = Vec::new();
+    let mut argv = env::args().skip(1);
+    while let Some(arg) = argv.next() {
+        match arg.as_str() {
+            "--key-file" => key_file = argv.next().map(PathBuf::from),
+            "--format" => format = match argv.next().map(|f| f.parse()) {
+                Some(Ok(format)) => format,
+                _ => usage(),
+            },
+            "--batch" => batch_size = match argv.next().map(|n| n.parse()) {
+                Some(Ok(n)) => n,
+                _ => usage(),
+            },
+            _ => args.push(arg),
+        }
+    }
+    let dir = |i: usize| args.get(i).cloned().unwrap_or("data".to_owned());
+    let max_args = match args.first().map(|a| a.as_str()) {
+        Some("dump") | Some("get") => 3,
+        Some(_) => 2,
+        None => 0,
+    };
+    if args.is_empty() || args.len() > max_args {
+        usage();
+    }
+
+    let result = match args[0].as_str() {
+        "dump" => match args.get(1) {
+            Some(file) => dump(file),
+            None => usage(),
+        },
+        "verify" => verify(&dir(1)),
+        "repair" => repair(&dir(1)),
+        "stats" => stats(&dir(1), key_file),
+        "get" => match args.get(1) {
+            Some(key) => get(&dir(2), key_file, key),
+            None => usage(),
+        },
+        "list-keys" => list_keys(&dir(1), key_file),
+        "export" => export(&dir(1), key_file, format),
+        "import" => import(&dir(1), key_file, format, batch_size),
+        _ => usage(),
+    };
+    if let Err(e) = result {
+        println!("{} failed: {}", args[0], e);
+        process::exit(1);
+    }
+}
+
+
+fn usage() -> ! {
+    println!("{}", USAGE);
+    process::exit(2);
+}
+
+
+fn dump(file: &str) -> bitcask::Result<()> {
+    let stdout = io::stdout();
+    bitcask::dump(Path::new(file), &mut stdout.lock())
+}
+
+
+fn verify(path: &str) -> bitcask::Result<()> {
+    let problems = try!(bitcask::verify(path));
+    for problem in problems.iter() {
+        println!("{}", problem);
+    }
+    if !problems.is_empty() {
+        process::exit(1);
+    }
+    println!("{} is consistent", path);
+    Ok(())
+}
+
+
+fn repair(path: &str) -> bitcask::Result<()> {
+    let repaired = try!(bitcask::repair(path));
+    for &(file_id, ref recovery) in repaired.iter() {
+        println!("{}: cut {} data bytes and {} hint bytes, added {} hints",
+                 file_id, recovery.data_discarded, recovery.hint_discarded, recovery.hints_added);
+    }
+    if repaired.is_empty() {
+        println!("nothing to repair in {}", path);
+    }
+    Ok(())
+}
+
+
+fn stats(path: &str, key_file: Option<PathBuf>) -> bitcask::Result<()> {
+    let bitcask = try!(open(path, key_file));
+    println!("file live_keys live_bytes dead_keys dead_bytes fragmentation");
+    for stats in bitcask.file_stats() {
+        println!("{} {} {} {} {} {}%", stats.file_id, stats.live_keys, stats.live_bytes,
+                 stats.dead_keys, stats.dead_bytes, stats.fragmentation());
+    }
+    Ok(())
+}
+
+
+fn get(path: &str, key_file: Option<PathBuf>, key: &str) -> bitcask::Result<()> {
+    let bitcask = try!(open(path, key_file));
+    match try!(bitcask.get(key)) {
+        Some(value) => {
+            let stdout = io::stdout();
+            let mut stdout = stdout.lock();
+            try!(stdout.write_all(&value));
+            try!(stdout.write_all(b"\n"));
+            Ok(())
+        },
+        None => {
+            println!("{} not found", key);
+            process::exit(1);
+        },
+    }
+}
+
+
+fn list_keys(path: &str, key_file: Option<PathBuf>) -> bitcask::Result<()> {
+    let bitcask = try!(open(path, key_file));
+    let mut keys = bitcask.keys().collect::<Vec<Vec<u8>>>();
+    keys.sort();
+    for key in keys {
+        println!("{}", bitcask::escape(&key));
+    }
+    Ok(())
+}
+
+
+fn export(path: &str, key_file: Option<PathBuf>, format: ExportFormat) -> bitcask::Result<()> {
+    let bitcask = try!(open(path, key_file));
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    try!(bitcask.export(&mut stdout, format));
+    try!(stdout.flush());
+    Ok(())
+}
+
+
+/// Needs the store to itself, like the server.
+fn import(path: &str, key_file: Option<PathBuf>, format: ExportFormat, batch_size: usize) -> bitcask::Result<()> {
+    let mut option = BitcaskOptions::default();
+    option.key_file = key_file;
+    let mut bitcask = try!(Bitcask::new(path.to_owned(), option));
+    let stdin = io::stdin();
+    let count = try!(bitcask.import(BufReader::new(stdin.lock()), format, batch_size));
+    println!("imported {} keys into {}", count, path);
+    Ok(())
+}
+
+
+/// Opens the store read-only, so that this works next to a running server.
+fn open(path: &str, key_file: Option<PathBuf>) -> bitcask::Result<Bitcask> {
+    let mut option = BitcaskOptions::default();
+    option.key_file = key_file;
+    Bitcask::open_read_only(path.to_owned(), option)
+}
@@ -0,0 +1,36 @@
+extern crate bitcask;
+
+use std::env;
+use std::process;
+
+
+fn main() {
+    let mut dry_run = false;
+    let mut path = None;
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--dry-run" | "-n" => dry_run = true,
+            _ if path.is_none() => path = Some(arg),
+            _ => {
+                println!("usage: bitcask-upgrade [--dry-run] [DIR]");
+                process::exit(2);
+            }
+        }
+    }
+    let path = path.unwrap_or("data".to_owned());
+
+    let report = match bitcask::upgrade(&path, dry_run) {
+        Ok(report) => report,
+        Err(e) => {
+            println!("upgrade {} failed: {}", path, e);
+            process::exit(1);
+        }
+    };
+    let action = if dry_run { "would upgrade" } else { "upgraded" };
+    for file in report.upgraded.iter() {
+        println!("{} {}", action, file.display());
+    }
+    for file in report.current.iter() {
+        println!("up to date {}", file.display());
+    }
+}
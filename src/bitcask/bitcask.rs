@@ -1,215 +1,1577 @@
+use std::cmp;
 use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fs;
+use std::io::BufRead;
+use std::io::Write;
+use std::mem;
+use std::path::PathBuf;
+use std::ops::RangeBounds;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::vec;
 
 use time;
 use std::path::Path;
 
+use bitcask::checkpoint::checkpoint;
+use bitcask::compression::Compression;
+use bitcask::encryption::ENCRYPTED;
+use bitcask::encryption::Keyring;
+use bitcask::export::ExportFormat;
+use bitcask::export::ExportReader;
+use bitcask::filter::CompactionFilter;
 use bitcask::hint_file::HintEntry;
 use bitcask::data_file::DataFile;
 use bitcask::data_file::DataEntry;
+use bitcask::data_file::TOMBSTONE;
 use bitcask::hint_file::HintFile;
+use bitcask::keydir::Entry;
+use bitcask::keydir::KeyDir;
+use bitcask::lock::DirLock;
+use bitcask::manifest::Manifest;
+use bitcask::merge::Candidate;
+use bitcask::merge::MergeContext;
+use bitcask::merge::MergeJob;
+use bitcask::merge::MergeOutcome;
+use bitcask::merge::MergeStats;
+use bitcask::merge::MergeWindow;
+use bitcask::merge::pick_files;
+use bitcask::throttle::Throttle;
+use bitcask::recovery::recover_tail;
+use bitcask::snapshot::Iter;
+use bitcask::snapshot::Snapshot;
+use bitcask::snapshot::read_value;
+use bitcask::snapshot::require_keyring;
+use bitcask::stats::FileStats;
+use bitcask::stats::StatsTable;
+use ::error::ErrorKind;
+use ::error::Result;
 
 
-const TOMBSTONE: [u8;4] = [0, 0, 0, 0];
+const TOMBSTONE_VALUE: [u8;4] = [0, 0, 0, 0];
 const FILE_SIZE: u64 = 1024 * 1024 * 100;
-
-struct Entry {
-    timestamp: u32,
-    value_size: u32,
-    value_pos: u64,
-    file_id: u32,
-}
+const MAX_KEY_SIZE: usize = 1024 * 64;
+const MAX_VALUE_SIZE: u64 = 1024 * 1024 * 64;
+const COMPRESSION_THRESHOLD: u64 = 256;
 
 
 pub struct Bitcask {
-    entries: HashMap<String, Entry>,
-    data_files: HashMap<u32, DataFile>,
-    write_data: DataFile,
-    write_hint: HintFile,
+    /// Shared with snapshots, and copied on write while there are any.
+    entries: Arc<KeyDir>,
+    /// Live and dead records per data file, following the keydir.
+    stats: StatsTable,
+    /// Read handles of every data file, the active one included.
+    data_files: HashMap<u32, Arc<DataFile>>,
+    keyring: Option<Arc<Keyring>>,
+    writer: Option<Writer>,
+    /// When read-only, the newest hint file, read up to where `refresh`
+    /// continues.
+    tail: Option<HintFile>,
     write_id: u32,
+    /// Progress of the running merge, shared with its `MergeJob`.
+    merge_stats: Arc<Mutex<MergeStats>>,
+    /// Limits the I/O of merges, shared with the running one.
+    merge_throttle: Arc<Throttle>,
     option: BitcaskOptions,
     path: String,
 }
 
+
+/// The files being appended to, and the lock that makes us their only
+/// writer. There is none when opened read-only.
+struct Writer {
+    data: DataFile,
+    hint: HintFile,
+    manifest: Manifest,
+    // Declared last so that it is released after the files are closed.
+    _lock: DirLock,
+}
+
+/// A single mutation applied by `Bitcask::write_batch`. Keys are raw bytes.
+pub enum WriteOp {
+    Put(Vec<u8>, Vec<u8>),
+    Delete(Vec<u8>),
+}
+
+#[derive(Clone)]
 pub struct BitcaskOptions {
-    file_size_limit: u64
+    /// Size after which the active data file is sealed and a new one started.
+    pub file_size_limit: u64,
+    /// Longest key `put` accepts, in bytes.
+    pub max_key_size: usize,
+    /// Largest value `put` accepts, in bytes.
+    pub max_value_size: u64,
+    /// Whether a partial record left at the end of the active files by a
+    /// crash is cut off on open. When false, `new` fails instead. A corrupt
+    /// record with others after it fails `new` either way.
+    pub truncate_torn_tail: bool,
+    /// Whether the keydir is kept sorted, which makes `range` and `prefix`
+    /// cheap at some cost to every other operation.
+    pub ordered_keydir: bool,
+    /// Codec new values are compressed with. Values already written keep
+    /// theirs until a merge rewrites them.
+    pub compression: Compression,
+    /// Values shorter than this are stored as they are.
+    pub compression_threshold: u64,
+    /// Keys to encrypt records with, see `Keyring`. Without it records are
+    /// written in the clear, and encrypted ones can't be read.
+    pub key_file: Option<PathBuf>,
+    /// Percentage of dead bytes at which a sealed file is merged.
+    pub merge_fragmentation: u64,
+    /// Dead bytes across sealed files at which all of them with any are
+    /// merged.
+    pub merge_dead_bytes: u64,
+    /// Files are only merged once they are this old.
+    pub merge_min_file_age: Duration,
+    /// Sealed files smaller than this are merged together.
+    pub merge_small_file_size: u64,
+    /// When background merges may start. They may start any time if unset.
+    pub merge_window: Option<MergeWindow>,
+    /// Bytes per second merges may read and write, 0 for no limit. It can
+    /// be changed later with `set_merge_rate_limit`.
+    pub merge_rate_limit: u64,
+    /// Decides what merges do with each live record they copy.
+    pub compaction_filter: Option<Arc<dyn CompactionFilter>>,
 }
 
 
 impl Bitcask {
-    pub fn new(path: String, option: BitcaskOptions) -> Bitcask {
-        let mut latest_file_id: u32 = 0;
+    pub fn new(path: String, option: BitcaskOptions) -> Result<Bitcask> {
+        Bitcask::open(path, option, false)
+    }
+
+    /// Opens the directory for reading only, e.g. to inspect the store of a
+    /// running server. It neither takes the directory lock nor creates or
+    /// repairs any file, and every mutating method fails with
+    /// `ErrorKind::ReadOnly`.
+    pub fn open_read_only(path: String, option: BitcaskOptions) -> Result<Bitcask> {
+        Bitcask::open(path, option, true)
+    }
+
+    fn open(path: String, option: BitcaskOptions, read_only: bool) -> Result<Bitcask> {
+        let lock = if read_only {
+            None
+        } else {
+            Some(try!(DirLock::acquire(&path)))
+        };
         let mut data_files = HashMap::new();
-        let mut entries = HashMap::new();
-
-        {
-            let p = Path::new(&path);
-            let files = p.read_dir().unwrap();
-            for file in files {
-                let file_path = match file {
-                    Ok(f) => f.path(),
-                    Err(_) => continue
-                };
-                let filename = match file_path.file_stem() {
-                    Some(f) => f.to_string_lossy(),
-                    None => continue
-                };
-                let file_id = match filename.parse::<u32>() {
-                    Ok(i) => i,
-                    Err(_) => continue
-                };
-                latest_file_id = file_id;
-                let ext = match file_path.extension() {
-                    Some(e) => e.to_string_lossy(),
-                    None => continue
-                };
-                if ext == "data" {
-                    let data_file = DataFile::new(&path, file_id, None);
-                    data_files.insert(file_id, data_file);
-                } else if ext == "hint" {
-                    let hint_file = HintFile::new(&path, file_id, None);
-                    for hint_entry in hint_file {
-                        let entry = Entry {
-                            timestamp: hint_entry.timestamp,
-                            value_size: hint_entry.value_size,
-                            value_pos: hint_entry.value_pos,
-                            file_id: file_id,
-                        };
-                        let key = String::from_utf8(hint_entry.key);
-                        entries.insert(key.unwrap(), entry);
-                    }
+        let mut entries = KeyDir::new(option.ordered_keydir);
+        let mut stats = StatsTable::new();
+        let keyring = match option.key_file {
+            Some(ref key_file) => Some(Arc::new(try!(Keyring::load(key_file)))),
+            None => None,
+        };
+        let mut tail = None;
+
+        let mut manifest = match try!(Manifest::load(&path)) {
+            Some(manifest) => manifest,
+            None => try!(Manifest::scan(&path)),
+        };
+        if !read_only {
+            try!(manifest.collect_garbage(&path));
+            try!(manifest.store(&path));
+            try!(recover_tail(&path, manifest.active, option.truncate_torn_tail, false));
+        }
+        let latest_file_id = manifest.active;
+
+        // Oldest first, so that later records win.
+        for file_id in manifest.live_files() {
+            if Path::new(&path).join(format!("{}.data", file_id)).exists() {
+                let data_file = try!(DataFile::new(&path, file_id, None));
+                data_files.insert(file_id, Arc::new(data_file));
+            }
+            if Path::new(&path).join(format!("{}.hint", file_id)).exists() {
+                let mut hint_file = try!(HintFile::new(&path, file_id, None));
+                while let Some(hint_entry) = try!(hint_file.read_entry()) {
+                    try!(apply_hint(&mut entries, &mut stats, keyring.as_deref(), file_id, hint_entry));
+                }
+                if read_only && file_id == latest_file_id {
+                    tail = Some(hint_file);
                 }
             }
         }
 
-        let write_data = DataFile::new(&path, latest_file_id, Some(0));
-        let write_hint = HintFile::new(&path, latest_file_id, Some(0));
+        let writer = match lock {
+            Some(lock) => Some(Writer {
+                data: try!(DataFile::new(&path, latest_file_id, Some(0))),
+                hint: try!(HintFile::new(&path, latest_file_id, Some(0))),
+                manifest: manifest,
+                _lock: lock,
+            }),
+            None => None,
+        };
+        if writer.is_some() && !data_files.contains_key(&latest_file_id) {
+            let data_file = try!(DataFile::new(&path, latest_file_id, None));
+            data_files.insert(latest_file_id, Arc::new(data_file));
+        }
 
-        Bitcask {
-            entries: entries,
+        Ok(Bitcask {
+            entries: Arc::new(entries),
+            stats: stats,
             data_files: data_files,
-            write_data: write_data,
-            write_hint: write_hint,
+            keyring: keyring,
+            writer: writer,
+            tail: tail,
             write_id: latest_file_id,
+            merge_stats: Arc::new(Mutex::new(MergeStats::default())),
+            merge_throttle: Arc::new(Throttle::new(option.merge_rate_limit)),
             option: option,
             path: path,
-        }
+        })
     }
 
-    pub fn get(&mut self, key: String) -> Option<Vec<u8>> {
-        let entry = match self.entries.get(&key) {
-            None => return None,
-            Some(e) => e
+    pub fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>> {
+        read_value(&self.entries, &self.data_files, self.keyring.as_deref(), key.as_ref())
+    }
+
+    /// A consistent view of the store as it is now, which later writes
+    /// don't change. It shares the keydir, so while it is alive the first
+    /// write copies the whole keydir, in O(n) and under the write lock.
+    /// Long lived snapshots of large stores would need a persistent map
+    /// instead.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot::new(self.entries.clone(), self.data_files.clone(), self.keyring.clone())
+    }
+
+    /// Writes a copy of the store as it is now to `dest`, which must be empty
+    /// or not exist yet, without stopping it; see `checkpoint::checkpoint`.
+    /// Only the writer knows where the active file is consistent, so a store
+    /// opened read-only fails with `ErrorKind::ReadOnly`.
+    pub fn checkpoint<P: AsRef<Path>>(&self, dest: P) -> Result<()> {
+        let writer = match self.writer {
+            Some(ref writer) => writer,
+            None => return Err(ErrorKind::ReadOnly.into()),
         };
-        let file_id = entry.file_id;
-        let mut data_file = match self.data_files.get_mut(&file_id) {
-            None => return None,
-            Some(data_file) => data_file
+        let active = writer.manifest.active;
+        let path = Path::new(&self.path);
+        let data_len = try!(fs::metadata(path.join(format!("{}.data", active)))).len();
+        let hint_len = try!(fs::metadata(path.join(format!("{}.hint", active)))).len();
+        checkpoint(path, dest.as_ref(), &writer.manifest, (data_len, hint_len))
+    }
+
+    /// Catches up with the writer of a directory opened read-only: new
+    /// entries of the hint file being followed, and of files the manifest
+    /// lists since, are applied to the keydir. Returns how many entries were applied. A
+    /// store opened for writing is always up to date.
+    pub fn refresh(&mut self) -> Result<usize> {
+        if !self.is_read_only() {
+            return Ok(0);
+        }
+
+        // The writer lists a new file only once it is done with the one
+        // before, so after reading the manifest the current file can be
+        // followed to its end.
+        let manifest = try!(Manifest::load(&self.path));
+        let mut applied = try!(self._apply_tail());
+        let manifest = match manifest {
+            Some(ref manifest) => manifest,
+            None => return Ok(applied),
         };
-        let mut value = vec![0; entry.value_size as usize];
-        data_file.read_exact(entry.value_pos, value.as_mut_slice()).expect("read exact");
-        Some(value)
+        // Files listed before the one followed so far are either applied
+        // already or the output of a merge, which holds nothing newer.
+        let live_files = manifest.live_files();
+        let start = live_files.iter().position(|&file_id| file_id == self.write_id).map_or(0, |i| i + 1);
+        try!(self._apply_merges(&live_files[..start], &live_files));
+        if manifest.active == self.write_id {
+            return Ok(applied);
+        }
+        for &file_id in live_files[start..].iter() {
+            if self.data_files.contains_key(&file_id) {
+                continue;
+            }
+            let data_file = try!(DataFile::new(&self.path, file_id, None));
+            self.data_files.insert(file_id, Arc::new(data_file));
+            self.tail = Some(try!(HintFile::new(&self.path, file_id, None)));
+            applied += try!(self._apply_tail());
+        }
+        self.write_id = manifest.active;
+        Ok(applied)
     }
 
-    fn _put_file(&mut self, key: &[u8], value: Vec<u8>) -> Result<u64, String> {
-        let key_bytes = key.to_vec();
+    /// Moves the keys in files a merge replaced to its outputs, which are
+    /// the new files of `merged`, and lets go of the replaced files.
+    fn _apply_merges(&mut self, merged: &[u32], live_files: &[u32]) -> Result<()> {
+        let live = live_files.iter().cloned().collect::<HashSet<u32>>();
+        if self.data_files.keys().all(|file_id| live.contains(file_id)) {
+            return Ok(());
+        }
+        for &file_id in merged.iter() {
+            if self.data_files.contains_key(&file_id) {
+                continue;
+            }
+            let data_file = try!(DataFile::new(&self.path, file_id, None));
+            self.data_files.insert(file_id, Arc::new(data_file));
+            let mut hint_file = try!(HintFile::new(&self.path, file_id, None));
+            while let Some(hint_entry) = try!(hint_file.read_entry_strict()) {
+                // Keys written again since the merge keep their newer value.
+                let key = if hint_entry.flags & ENCRYPTED != 0 {
+                    try!(try!(require_keyring(self.keyring.as_deref())).open(&hint_entry.key, b""))
+                } else {
+                    hint_entry.key.clone()
+                };
+                let moved = match self.entries.get(&key) {
+                    Some(entry) => !live.contains(&entry.file_id),
+                    None => false,
+                };
+                if moved && hint_entry.flags & TOMBSTONE == 0 {
+                    try!(apply_hint(Arc::make_mut(&mut self.entries), &mut self.stats, self.keyring.as_deref(),
+                                    file_id, hint_entry));
+                } else {
+                    self.stats.add(file_id, hint_entry.data_len(), false);
+                }
+            }
+        }
+        // What is left in the replaced files, the merge dropped.
+        Arc::make_mut(&mut self.entries).retain(|entry| live.contains(&entry.file_id));
+        let stale = self.data_files.keys().filter(|file_id| !live.contains(file_id)).cloned().collect::<Vec<u32>>();
+        for file_id in stale {
+            self.data_files.remove(&file_id);
+            self.stats.remove(file_id);
+        }
+        Ok(())
+    }
+
+    fn _apply_tail(&mut self) -> Result<usize> {
+        let tail = match self.tail {
+            Some(ref mut tail) => tail,
+            None => return Ok(0),
+        };
+        let mut applied = 0;
+        while let Some(hint_entry) = try!(tail.read_entry()) {
+            try!(apply_hint(Arc::make_mut(&mut self.entries), &mut self.stats, self.keyring.as_deref(),
+                            tail.file_id, hint_entry));
+            applied += 1;
+        }
+        Ok(applied)
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.writer.is_none()
+    }
+
+    /// Checks `op` against the key and value size limits, the same way
+    /// `write_batch` does before it writes anything.
+    pub fn check_write(&self, op: &WriteOp) -> Result<()> {
+        check_write(&self.option, op)
+    }
+
+    pub fn write_batch(&mut self, ops: Vec<WriteOp>) -> Result<()> {
         let ts = time::get_time().sec as u32;
-        let value_size = value.len() as u32;
-        let key_size = key_bytes.len() as u8;
-        let data_entry = DataEntry{
-            crc: 0,
-            timestamp: ts,
-            key_size: key_size,
-            value_size: value_size,
-            key: key_bytes.clone(),
-            value: value
+        self.write_timestamped(ops.into_iter().map(|op| (op, ts)).collect())
+    }
+
+    /// `write_batch` with the timestamp of each record given.
+    fn write_timestamped(&mut self, ops: Vec<(WriteOp, u32)>) -> Result<()> {
+        let writer = match self.writer {
+            Some(ref mut writer) => writer,
+            None => return Err(ErrorKind::ReadOnly.into()),
         };
-        let value_pos = try!(self.write_data.write(&data_entry));
+        if ops.is_empty() {
+            return Ok(());
+        }
+
+        for &(ref op, _) in ops.iter() {
+            try!(check_write(&self.option, op));
+        }
+
+        let mut keys = Vec::with_capacity(ops.len());
+        let mut data_entries = Vec::with_capacity(ops.len());
+        for (op, ts) in ops {
+            let (key, value) = match op {
+                WriteOp::Put(key, value) => (key, Some(value)),
+                WriteOp::Delete(key) => (key, None),
+            };
+            let is_delete = value.is_none();
+            data_entries.push(try!(encode_record(&self.option, self.keyring.as_deref(), &key, value, ts)));
+            keys.push((key, is_delete));
+        }
+        let positions = try!(writer.data.write_entries(&data_entries));
+
+        let hint_entries = data_entries.iter().zip(positions.iter())
+            .map(|(data_entry, &value_pos)| {
+                HintEntry{
+                    timestamp: data_entry.timestamp,
+                    flags: data_entry.flags,
+                    key_size: data_entry.key_size,
+                    value_size: data_entry.value_size,
+                    value_pos: value_pos,
+                    key: data_entry.key.clone(),
+                }
+            })
+            .collect::<Vec<HintEntry>>();
+        try!(writer.hint.write_entries(&hint_entries));
 
-        let hint_entry = HintEntry{
-            timestamp: ts,
-            key_size: key_size,
-            value_size: value_size,
-            value_pos: value_pos,
-            key: key_bytes,
+        let file_id = writer.data.file_id;
+        let entries = Arc::make_mut(&mut self.entries);
+        for ((key, is_delete), (data_entry, &value_pos)) in keys.into_iter().zip(data_entries.iter().zip(positions.iter())) {
+            let key_len = key.len();
+            let old = if is_delete {
+                entries.remove(&key)
+            } else {
+                entries.insert(key, Entry{
+                    timestamp: data_entry.timestamp,
+                    flags: data_entry.flags,
+                    value_size: data_entry.value_size,
+                    value_pos: value_pos,
+                    file_id: file_id
+                })
+            };
+            if let Some(old) = old {
+                self.stats.supersede(key_len, &old);
+            }
+            self.stats.add(file_id, data_entry.encoded_len(), !is_delete);
+        }
+
+        if positions[positions.len() - 1] >= self.option.file_size_limit {
+            try!(self._new_write_file());
+        }
+
+        Ok(())
+    }
+
+    fn _new_write_file(&mut self) -> Result<()> {
+        let writer = match self.writer {
+            Some(ref mut writer) => writer,
+            None => return Err(ErrorKind::ReadOnly.into()),
         };
-        self.write_hint.write(&hint_entry).expect("write entry hint");
+        let sealed_id = self.write_id;
+        self.write_id = writer.manifest.next_file_id();
+        writer.hint = try!(HintFile::new(&self.path, self.write_id, Some(0)));
+        writer.data = try!(DataFile::new(&self.path, self.write_id, Some(0)));
+        // Until the manifest lists them, the new files are leftovers that
+        // the next open removes.
+        writer.manifest.sealed.push(sealed_id);
+        writer.manifest.active = self.write_id;
+        try!(writer.manifest.store(&self.path));
+        let data_file = try!(DataFile::new(&self.path, self.write_id, None));
+        self.data_files.insert(self.write_id, Arc::new(data_file));
+        Ok(())
+    }
 
-        if value_pos >= self.option.file_size_limit {
-            self._new_write_file();
+    pub fn delete<K: AsRef<[u8]>>(&mut self, key: K) -> Result<()> {
+        self.write_batch(vec![WriteOp::Delete(key.as_ref().to_vec())])
+    }
+
+    pub fn put<K: AsRef<[u8]>>(&mut self, key: K, value: Vec<u8>) -> Result<()> {
+        self.write_batch(vec![WriteOp::Put(key.as_ref().to_vec(), value)])
+    }
+
+    /// Every live key, in order if the keydir is ordered. They are all
+    /// copied into a `Vec` first.
+    pub fn keys(&self) -> vec::IntoIter<Vec<u8>> {
+        self.entries.keys().into_iter()
+    }
+
+    /// Scans every live key and value, in order if the keydir is ordered.
+    /// Every key is copied first, see `Iter`.
+    pub fn iter(&self) -> Iter {
+        self.snapshot().iter()
+    }
+
+    /// Folds `f` over every live key and value, like `Iterator::fold`.
+    pub fn fold<A, F>(&self, acc: A, mut f: F) -> Result<A>
+        where F: FnMut(A, Vec<u8>, Vec<u8>) -> A
+    {
+        let mut acc = acc;
+        for item in self.iter() {
+            let (key, value) = try!(item);
+            acc = f(acc, key, value);
         }
+        Ok(acc)
+    }
 
-        Ok(value_pos)
+    /// Scans the keys in `range`, in order.
+    pub fn range<K: AsRef<[u8]>, R: RangeBounds<K>>(&self, range: R) -> Iter {
+        self.snapshot().range(range)
     }
 
-    fn _new_write_file(&mut self) {
-        self.write_id += 1;
-        self.write_hint = HintFile::new(&self.path, self.write_id, Some(0));
-        self.write_data = DataFile::new(&self.path, self.write_id, Some(0));
+    /// Scans the keys starting with `prefix`, in order.
+    pub fn prefix<P: AsRef<[u8]>>(&self, prefix: P) -> Iter {
+        self.snapshot().prefix(prefix)
     }
 
-    pub fn delete(&mut self, key: String) -> Result<(), String> {
-        self.entries.remove(&key);
-        try!(self._put_file(key.as_bytes(), TOMBSTONE.to_vec()));
-        Ok(())
+    /// Writes every live key and value to `out` as of now, in a format
+    /// other stores can `import`; see `ExportFormat`. Returns how many keys
+    /// were written.
+    pub fn export<W: Write>(&self, out: &mut W, format: ExportFormat) -> Result<usize> {
+        self.snapshot().export(out, format)
     }
 
-    pub fn put(&mut self, key: String, value: Vec<u8>) -> Result<(), String> {
-        let ts = time::get_time().sec as u32;
-        let value_size = value.len() as u32;
-        let value_pos = try!(self._put_file(key.as_bytes(), value));
+    /// Puts every key and value of an export, `batch_size` at a time in one
+    /// write each. Records keep their timestamp but are otherwise written
+    /// anew, with the store's own compression and encryption. Returns how
+    /// many were read. The store keeps neither expiry nor client flags, so
+    /// those are advisory and dropped.
+    pub fn import<R: BufRead>(&mut self, reader: R, format: ExportFormat, batch_size: usize) -> Result<usize> {
+        let batch_size = cmp::max(batch_size, 1);
+        let mut batch = Vec::with_capacity(batch_size);
+        let mut count = 0;
+        for record in try!(ExportReader::new(reader, format)) {
+            let record = try!(record);
+            count += 1;
+            batch.push((WriteOp::Put(record.key, record.value), record.timestamp));
+            if batch.len() >= batch_size {
+                try!(self.write_timestamped(mem::replace(&mut batch, Vec::with_capacity(batch_size))));
+            }
+        }
+        try!(self.write_timestamped(batch));
+        Ok(count)
+    }
+
+    /// Live and dead records of every data file, oldest first.
+    pub fn file_stats(&self) -> Vec<FileStats> {
+        let mut file_ids = self.data_files.keys().cloned().collect::<Vec<u32>>();
+        file_ids.sort();
+        file_ids.into_iter().map(|file_id| self.stats.get(file_id)).collect()
+    }
+
+    /// Rewrites every sealed file at once, dropping what is dead, and
+    /// waits for it to finish. See `start_merge` for merging in the
+    /// background.
+    pub fn merge(&mut self) -> Result<()> {
+        let mut job = match try!(self.start_merge(true)) {
+            Some(job) => job,
+            None => return Ok(()),
+        };
+        let result = {
+            let db = &mut *self;
+            job.run(&mut || db.reserve_merge_file())
+        };
+        match result {
+            Ok(()) => self.finish_merge(job),
+            Err(e) => {
+                try!(self.abort_merge(job));
+                Err(e)
+            },
+        }
+    }
+
+    /// Starts merging the sealed files the merge options pick, or all of
+    /// them if `everything`. Returns `None` if there is nothing to merge, a
+    /// merge is running already, merges are paused or, unless `everything`,
+    /// `merge_window` is closed.
+    ///
+    /// The job is `run` without the store, which only needs to be borrowed
+    /// again for `reserve_merge_file` and at last `finish_merge`, or
+    /// `abort_merge` if it failed. Until then the job keeps the keydir as
+    /// it is now, so the first write meanwhile copies the whole keydir, in
+    /// O(n) and under the write lock, like after `snapshot`.
+    pub fn start_merge(&mut self, everything: bool) -> Result<Option<MergeJob>> {
+        let writer = match self.writer {
+            Some(ref writer) => writer,
+            None => return Err(ErrorKind::ReadOnly.into()),
+        };
+        let mut merge_stats = try!(self.merge_stats.lock()
+            .map_err(|_| ErrorKind::Msg("merge stats lock poisoned".to_owned())));
+        if merge_stats.running || self.merge_throttle.is_paused() || self.merge_throttle.is_cancelled() {
+            return Ok(None);
+        }
+        let file_ids = if everything {
+            writer.manifest.sealed.clone()
+        } else {
+            if !self.option.merge_window.map_or(true, |window| window.is_open()) {
+                return Ok(None);
+            }
+            let candidates = writer.manifest.sealed.iter()
+                .filter_map(|file_id| self.data_files.get(file_id))
+                .map(|data_file| Candidate {
+                    stats: self.stats.get(data_file.file_id),
+                    created: data_file.header.created,
+                })
+                .collect::<Vec<Candidate>>();
+            pick_files(&candidates, &self.option, time::get_time().sec as u32)
+        };
+        if file_ids.is_empty() {
+            return Ok(None);
+        }
+
+        let mut inputs = Vec::with_capacity(file_ids.len());
+        let mut keep_tombstones = HashSet::new();
+        let mut skipped = false;
+        for file_id in writer.manifest.sealed.iter() {
+            if !file_ids.contains(file_id) {
+                skipped = true;
+                continue;
+            }
+            if let Some(data_file) = self.data_files.get(file_id) {
+                inputs.push(data_file.clone());
+            }
+            if skipped {
+                keep_tombstones.insert(*file_id);
+            }
+        }
+
+        *merge_stats = MergeStats {
+            running: true,
+            files: file_ids.clone(),
+            bytes_to_read: file_ids.iter().map(|&file_id| {
+                let stats = self.stats.get(file_id);
+                stats.live_bytes + stats.dead_bytes
+            }).sum(),
+            bytes_read: 0,
+            bytes_written: 0,
+            ..merge_stats.clone()
+        };
+        let context = MergeContext {
+            option: self.option.clone(),
+            path: self.path.clone(),
+            stats: self.merge_stats.clone(),
+            throttle: self.merge_throttle.clone(),
+        };
+        Ok(Some(MergeJob::new(inputs, keep_tombstones, self.entries.clone(), self.keyring.clone(), context)))
+    }
+
+    /// Reserves the id of a new file for a merge to write. It is listed as
+    /// merging, so that nothing else uses it, and removed on the next open
+    /// if the merge doesn't finish.
+    pub fn reserve_merge_file(&mut self) -> Result<u32> {
+        let writer = match self.writer {
+            Some(ref mut writer) => writer,
+            None => return Err(ErrorKind::ReadOnly.into()),
+        };
+        let file_id = writer.manifest.next_file_id();
+        writer.manifest.merging.insert(file_id);
+        try!(writer.manifest.store(&self.path));
+        Ok(file_id)
+    }
 
-        let entry = Entry{
-            timestamp: ts,
-            value_size: value_size,
-            value_pos: value_pos,
-            file_id: self.write_data.file_id
+    /// Replaces the inputs of a merge that ran with its outputs. Keys
+    /// written since the merge started keep their newer records. Inputs are
+    /// removed as soon as no snapshot, iterator or read uses them any more.
+    ///
+    /// If the keydir still points into an input at a record the merge
+    /// didn't copy, the merge is abandoned instead and the input kept.
+    pub fn finish_merge(&mut self, job: MergeJob) -> Result<()> {
+        let MergeOutcome { inputs, outputs, moves, tombstones } = job.into_outcome();
+        let current = moves.iter()
+            .map(|m| self.entries.get(&m.key).map_or(false, |entry| {
+                entry.file_id == m.file_id && entry.value_pos == m.value_pos
+            }))
+            .collect::<Vec<bool>>();
+        for &file_id in inputs.iter() {
+            let moved = moves.iter().zip(current.iter()).filter(|&(m, &c)| c && m.file_id == file_id).count();
+            if self.stats.get(file_id).live_keys > moved as u64 {
+                try!(self.discard_merge(&outputs));
+                return Err(format!("merge missed live records of {}.data, keeping it", file_id).into());
+            }
+        }
+
+        let writer = match self.writer {
+            Some(ref mut writer) => writer,
+            None => return Err(ErrorKind::ReadOnly.into()),
         };
-        self.entries.insert(key.to_owned(), entry);
+        let mut output_files = HashMap::new();
+        for &file_id in outputs.iter() {
+            output_files.insert(file_id, Arc::new(try!(DataFile::new(&self.path, file_id, None))));
+        }
+
+        // The outputs take the place of the last input, so that files listed
+        // after it still win on open.
+        let mut manifest = writer.manifest.clone();
+        let last_input = manifest.sealed.iter().rposition(|file_id| inputs.contains(file_id));
+        manifest.sealed = manifest.sealed.iter().enumerate()
+            .flat_map(|(i, file_id)| if Some(i) == last_input {
+                outputs.clone()
+            } else if inputs.contains(file_id) {
+                Vec::new()
+            } else {
+                vec![*file_id]
+            })
+            .collect();
+        for file_id in outputs.iter() {
+            manifest.merging.remove(file_id);
+        }
+        try!(manifest.store(&self.path));
+        writer.manifest = manifest;
 
+        let input_bytes = inputs.iter().map(|&file_id| {
+            let stats = self.stats.get(file_id);
+            stats.live_bytes + stats.dead_bytes
+        }).sum::<u64>();
+        let mut output_bytes = 0;
+        let entries = Arc::make_mut(&mut self.entries);
+        for (m, current) in moves.into_iter().zip(current) {
+            output_bytes += m.len;
+            match m.entry {
+                Some(entry) => {
+                    self.stats.add(entry.file_id, m.len, current);
+                    if current {
+                        entries.insert(m.key, entry);
+                    }
+                },
+                None => if current {
+                    entries.remove(&m.key);
+                },
+            }
+        }
+        for (file_id, len) in tombstones {
+            self.stats.add(file_id, len, false);
+            output_bytes += len;
+        }
+        self.data_files.extend(output_files);
+
+        for &file_id in inputs.iter() {
+            self.stats.remove(file_id);
+            if let Some(data_file) = self.data_files.remove(&file_id) {
+                data_file.remove_on_drop();
+            }
+        }
+
+        if let Ok(mut merge_stats) = self.merge_stats.lock() {
+            merge_stats.running = false;
+            merge_stats.files.clear();
+            merge_stats.merges += 1;
+            merge_stats.files_merged += inputs.len() as u64;
+            merge_stats.bytes_reclaimed += input_bytes.saturating_sub(output_bytes);
+        }
         Ok(())
     }
 
-    #[allow(dead_code)]
-    pub fn merge(&mut self) {
+    /// Gives up on a merge, removing what it wrote.
+    pub fn abort_merge(&mut self, job: MergeJob) -> Result<()> {
+        self.discard_merge(&job.into_outcome().outputs)
+    }
+
+    fn discard_merge(&mut self, outputs: &[u32]) -> Result<()> {
+        if let Ok(mut merge_stats) = self.merge_stats.lock() {
+            merge_stats.running = false;
+            merge_stats.files.clear();
+        }
+        let writer = match self.writer {
+            Some(ref mut writer) => writer,
+            None => return Err(ErrorKind::ReadOnly.into()),
+        };
+        for &file_id in outputs.iter() {
+            let _ = fs::remove_file(Path::new(&self.path).join(format!("{}.data", file_id)));
+            let _ = fs::remove_file(Path::new(&self.path).join(format!("{}.hint", file_id)));
+            writer.manifest.merging.remove(&file_id);
+        }
+        writer.manifest.store(&self.path)
+    }
+
+    /// Progress of the running merge, and totals of the finished ones.
+    pub fn merge_stats(&self) -> MergeStats {
+        let mut stats = self.merge_stats.lock().map(|stats| stats.clone()).unwrap_or_default();
+        stats.paused = self.merge_throttle.is_paused();
+        stats.rate_limit = self.merge_throttle.rate();
+        stats
+    }
+
+    /// Changes how many bytes per second merges may read and write, the
+    /// running one included. 0 lifts the limit.
+    pub fn set_merge_rate_limit(&self, rate: u64) {
+        self.merge_throttle.set_rate(rate);
+    }
+
+    /// Stops the running merge where it is, and keeps new ones from
+    /// starting, until `resume_merge`.
+    pub fn pause_merge(&self) {
+        self.merge_throttle.pause();
+    }
+
+    pub fn resume_merge(&self) {
+        self.merge_throttle.resume();
+    }
+
+    /// Makes the running merge fail, even while paused, so that its job can
+    /// be aborted, and keeps new ones from starting. For shutting down, as
+    /// it can't be undone.
+    pub fn cancel_merge(&self) {
+        self.merge_throttle.cancel();
+    }
+}
+
+
+/// Builds the data record that stores `value` under `key`, or deletes `key`
+/// if it is `None`, compressed and encrypted as the options say.
+pub fn encode_record(option: &BitcaskOptions, keyring: Option<&Keyring>, key: &[u8], value: Option<Vec<u8>>,
+                     timestamp: u32) -> Result<DataEntry> {
+    let is_delete = value.is_none();
+    let value = value.unwrap_or_else(|| TOMBSTONE_VALUE.to_vec());
+    let (mut value, mut flags) = try!(compress_value(option, value, is_delete));
+    if is_delete {
+        flags |= TOMBSTONE;
+    }
+    // The value is bound to its key, so it can't be moved to another.
+    let key_bytes = match keyring {
+        Some(keyring) => {
+            if !is_delete {
+                value = try!(keyring.seal(&value, key));
+            }
+            flags |= ENCRYPTED;
+            try!(keyring.seal(key, b""))
+        },
+        None => key.to_vec(),
+    };
+    Ok(DataEntry {
+        crc: 0,
+        timestamp: timestamp,
+        flags: flags,
+        key_size: key_bytes.len() as u32,
+        value_size: value.len() as u64,
+        key: key_bytes,
+        value: value,
+    })
+}
+
+
+/// Fails if the key or value of `op` is over the configured size limits.
+fn check_write(option: &BitcaskOptions, op: &WriteOp) -> Result<()> {
+    let (key, value_size) = match *op {
+        WriteOp::Put(ref key, ref value) => (key, value.len() as u64),
+        WriteOp::Delete(ref key) => (key, 0),
+    };
+    if key.len() > option.max_key_size {
+        return Err(ErrorKind::KeyTooLarge(key.len(), option.max_key_size).into());
+    }
+    if value_size > option.max_value_size {
+        return Err(ErrorKind::ValueTooLarge(value_size, option.max_value_size).into());
+    }
+    Ok(())
+}
+
+
+/// Compresses a value if it is worth it, returning what to store and the
+/// record flags for it. Tombstones are always stored as they are.
+fn compress_value(option: &BitcaskOptions, value: Vec<u8>, is_delete: bool) -> Result<(Vec<u8>, u8)> {
+    let codec = option.compression;
+    if is_delete || codec == Compression::None || (value.len() as u64) < option.compression_threshold {
+        return Ok((value, 0));
+    }
+    let compressed = try!(codec.compress(&value));
+    if compressed.len() < value.len() {
+        Ok((compressed, codec.flags()))
+    } else {
+        Ok((value, 0))
+    }
+}
+
 
+/// Applies a hint entry of file `file_id` to the keydir.
+fn apply_hint(entries: &mut KeyDir, stats: &mut StatsTable, keyring: Option<&Keyring>, file_id: u32,
+              mut hint_entry: HintEntry) -> Result<()> {
+    let len = hint_entry.data_len();
+    if hint_entry.flags & ENCRYPTED != 0 {
+        hint_entry.key = try!(try!(require_keyring(keyring)).open(&hint_entry.key, b""));
     }
+    if hint_entry.flags & TOMBSTONE != 0 {
+        if let Some(old) = entries.remove(&hint_entry.key) {
+            stats.supersede(hint_entry.key.len(), &old);
+        }
+        stats.add(file_id, len, false);
+        return Ok(());
+    }
+
+    let key_len = hint_entry.key.len();
+    let old = entries.insert(hint_entry.key, Entry {
+        timestamp: hint_entry.timestamp,
+        flags: hint_entry.flags,
+        value_size: hint_entry.value_size,
+        value_pos: hint_entry.value_pos,
+        file_id: file_id,
+    });
+    if let Some(old) = old {
+        stats.supersede(key_len, &old);
+    }
+    stats.add(file_id, len, true);
+    Ok(())
 }
 
 
 impl Default for BitcaskOptions {
     fn default() -> BitcaskOptions {
         BitcaskOptions {
-            file_size_limit: FILE_SIZE
+            file_size_limit: FILE_SIZE,
+            max_key_size: MAX_KEY_SIZE,
+            max_value_size: MAX_VALUE_SIZE,
+            truncate_torn_tail: true,
+            ordered_keydir: false,
+            compression: Compression::None,
+            compression_threshold: COMPRESSION_THRESHOLD,
+            key_file: None,
+            merge_fragmentation: 50,
+            merge_dead_bytes: 1024 * 1024 * 1024,
+            merge_min_file_age: Duration::from_secs(0),
+            merge_small_file_size: FILE_SIZE / 10,
+            merge_window: None,
+            merge_rate_limit: 0,
+            compaction_filter: None,
         }
     }
 }
 
 
+/// An empty directory for a test, removed with what is in it once dropped.
+#[cfg(test)]
+pub struct TestDir(pub ::std::path::PathBuf);
+
+#[cfg(test)]
+impl TestDir {
+    pub fn path(&self) -> String {
+        self.0.to_string_lossy().into_owned()
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = ::std::fs::remove_dir_all(&self.0);
+    }
+}
+
+#[cfg(test)]
+pub fn test_dir(name: &str) -> TestDir {
+    let path = ::std::env::temp_dir().join(format!("bitcask-{}-{}", name, ::std::process::id()));
+    let _ = ::std::fs::remove_dir_all(&path);
+    ::std::fs::create_dir_all(&path).unwrap();
+    TestDir(path)
+}
+
 #[test]
 fn test_new() {
     let option = BitcaskOptions::default();
-    let bitcask = Bitcask::new("data".to_owned(), option);
+    let dir = test_dir("new");
+    let bitcask = Bitcask::new(dir.path(), option).unwrap();
 }
 
 #[test]
 fn test_put() {
     let option = BitcaskOptions::default();
-    let mut bitcask = Bitcask::new("data".to_owned(), option);
-    let key = "key".to_owned();
+    let dir = test_dir("put");
+    let mut bitcask = Bitcask::new(dir.path(), option).unwrap();
+    let key = "key";
     let val = "山东发生地方".to_owned().into_bytes();
-    bitcask.put(key.clone(), val.clone());
+    bitcask.put(key, val.clone()).unwrap();
 
-    assert_eq!(val, bitcask.get(key.clone()).unwrap());
+    assert_eq!(val, bitcask.get(key).unwrap().unwrap());
 }
 
 #[test]
 fn test_delete() {
     let option = BitcaskOptions::default();
-    let mut bitcask = Bitcask::new("data".to_owned(), option);
-    let key = "key".to_owned();
+    let dir = test_dir("delete");
+    let mut bitcask = Bitcask::new(dir.path(), option).unwrap();
+    let key = "key";
     let val = "山东发生地方".to_owned().into_bytes();
-    bitcask.put(key.clone(), val.clone());
-    bitcask.delete(key.clone());
-    assert_eq!(None, bitcask.get(key.clone()));
+    bitcask.put(key, val.clone()).unwrap();
+    bitcask.delete(key).unwrap();
+    assert_eq!(None, bitcask.get(key).unwrap());
+}
+
+
+#[test]
+fn test_write_batch() {
+    let option = BitcaskOptions::default();
+    let dir = test_dir("write_batch");
+    let mut bitcask = Bitcask::new(dir.path(), option).unwrap();
+    bitcask.write_batch(vec![
+        WriteOp::Put(b"batch_a".to_vec(), b"1".to_vec()),
+        WriteOp::Put(b"batch_b".to_vec(), b"2".to_vec()),
+        WriteOp::Delete(b"batch_a".to_vec()),
+    ]).unwrap();
+    assert_eq!(None, bitcask.get("batch_a").unwrap());
+    assert_eq!(b"2".to_vec(), bitcask.get("batch_b").unwrap().unwrap());
 }
 
+#[test]
+fn test_binary_key() {
+    let option = BitcaskOptions::default();
+    let dir = test_dir("binary_key");
+    let mut bitcask = Bitcask::new(dir.path(), option).unwrap();
+    let key = [0xff, 0xfe, 0x00, b'k'];
+    bitcask.put(&key, b"binary".to_vec()).unwrap();
+    assert_eq!(b"binary".to_vec(), bitcask.get(&key).unwrap().unwrap());
+    drop(bitcask);
+
+    let mut bitcask = Bitcask::new(dir.path(), BitcaskOptions::default()).unwrap();
+    assert_eq!(b"binary".to_vec(), bitcask.get(&key).unwrap().unwrap());
+}
+
+#[test]
+fn test_zero_value() {
+    // Stored like a tombstone, but it isn't one.
+    let dir = test_dir("zero_value");
+    let mut bitcask = Bitcask::new(dir.path(), BitcaskOptions::default()).unwrap();
+    bitcask.put("zeros", vec![0, 0, 0, 0]).unwrap();
+    bitcask.put("gone", vec![1]).unwrap();
+    bitcask.delete("gone").unwrap();
+    drop(bitcask);
+
+    let bitcask = Bitcask::new(dir.path(), BitcaskOptions::default()).unwrap();
+    assert_eq!(Some(vec![0, 0, 0, 0]), bitcask.get("zeros").unwrap());
+    assert_eq!(None, bitcask.get("gone").unwrap());
+}
+
+#[test]
+fn test_size_limits() {
+    let mut option = BitcaskOptions::default();
+    option.max_key_size = 300;
+    option.max_value_size = 10;
+    let dir = test_dir("size_limits");
+    let mut bitcask = Bitcask::new(dir.path(), option).unwrap();
+
+    let long_key = vec![b'k'; 300];
+    bitcask.put(&long_key, b"v".to_vec()).unwrap();
+    assert_eq!(b"v".to_vec(), bitcask.get(&long_key).unwrap().unwrap());
+
+    assert!(bitcask.put(vec![b'k'; 301], b"v".to_vec()).is_err());
+    assert!(bitcask.put("key", vec![0; 11]).is_err());
+    drop(bitcask);
+
+    let mut bitcask = Bitcask::new(dir.path(), BitcaskOptions::default()).unwrap();
+    assert_eq!(b"v".to_vec(), bitcask.get(&long_key).unwrap().unwrap());
+}
+
+#[test]
+fn test_exclusive_lock() {
+    let dir = test_dir("exclusive_lock");
+    let bitcask = Bitcask::new(dir.path(), BitcaskOptions::default()).unwrap();
+    match Bitcask::new(dir.path(), BitcaskOptions::default()) {
+        Err(e) => match *e.kind() {
+            ErrorKind::Locked(_) => (),
+            _ => panic!("unexpected error {:?}", e),
+        },
+        Ok(_) => panic!("opened twice"),
+    }
+    drop(bitcask);
+    Bitcask::new(dir.path(), BitcaskOptions::default()).unwrap();
+}
+
+#[test]
+fn test_read_only() {
+    let dir = test_dir("read_only");
+    let mut bitcask = Bitcask::new(dir.path(), BitcaskOptions::default()).unwrap();
+    bitcask.put("key", b"value".to_vec()).unwrap();
+
+    // Works next to the writer, which holds the lock.
+    let mut reader = Bitcask::open_read_only(dir.path(), BitcaskOptions::default()).unwrap();
+    assert!(reader.is_read_only());
+    assert_eq!(b"value".to_vec(), reader.get("key").unwrap().unwrap());
+    match reader.put("key", b"other".to_vec()) {
+        Err(e) => match *e.kind() {
+            ErrorKind::ReadOnly => (),
+            _ => panic!("unexpected error {:?}", e),
+        },
+        Ok(_) => panic!("read-only put succeeded"),
+    }
+    assert!(reader.delete("key").is_err());
+    assert_eq!(b"value".to_vec(), bitcask.get("key").unwrap().unwrap());
+}
+
+#[test]
+fn test_refresh() {
+    let mut option = BitcaskOptions::default();
+    option.file_size_limit = 64;
+    let dir = test_dir("refresh");
+    let mut bitcask = Bitcask::new(dir.path(), option).unwrap();
+    bitcask.put("a", b"1".to_vec()).unwrap();
+    bitcask.put("b", b"2".to_vec()).unwrap();
+
+    let mut reader = Bitcask::open_read_only(dir.path(), BitcaskOptions::default()).unwrap();
+    assert_eq!(b"1".to_vec(), reader.get("a").unwrap().unwrap());
+    assert_eq!(0, reader.refresh().unwrap());
+
+    // Enough to rotate through a few files.
+    for i in 0..10 {
+        bitcask.put(format!("key{}", i), vec![b'v'; 20]).unwrap();
+    }
+    bitcask.delete("a").unwrap();
+    bitcask.put("b", b"3".to_vec()).unwrap();
+
+    assert_eq!(12, reader.refresh().unwrap());
+    assert_eq!(None, reader.get("a").unwrap());
+    assert_eq!(b"3".to_vec(), reader.get("b").unwrap().unwrap());
+    assert_eq!(vec![b'v'; 20], reader.get("key9").unwrap().unwrap());
+
+    // The reader moves to the merge outputs and lets go of its inputs.
+    for i in 0..10 {
+        bitcask.delete(format!("key{}", i)).unwrap();
+    }
+    bitcask.put("key0", b"4".to_vec()).unwrap();
+    bitcask.merge().unwrap();
+    assert_eq!(1, bitcask.merge_stats().merges);
+    reader.refresh().unwrap();
+    let file_ids = |db: &Bitcask| db.file_stats().iter().map(|s| s.file_id).collect::<Vec<u32>>();
+    assert_eq!(file_ids(&bitcask), file_ids(&reader));
+    assert_eq!(b"3".to_vec(), reader.get("b").unwrap().unwrap());
+    assert_eq!(b"4".to_vec(), reader.get("key0").unwrap().unwrap());
+    assert_eq!(None, reader.get("key9").unwrap());
+}
+
+#[test]
+fn test_manifest_garbage() {
+    let dir = test_dir("manifest_garbage");
+    let path = dir.path();
+    let mut option = BitcaskOptions::default();
+    option.file_size_limit = 16;
+    let mut bitcask = Bitcask::new(path.clone(), option).unwrap();
+    bitcask.put("a", b"1".to_vec()).unwrap();
+    bitcask.put("b", b"2".to_vec()).unwrap();
+    drop(bitcask);
+
+    // Output of a merge that never finished, and a file nothing knows of.
+    let mut manifest = Manifest::load(&path).unwrap().unwrap();
+    let merge_id = manifest.next_file_id();
+    manifest.merging.insert(merge_id);
+    manifest.store(&path).unwrap();
+    DataFile::new(&path, merge_id, Some(0)).unwrap().write(&DataEntry {
+        crc: 0,
+        timestamp: 0,
+        flags: 0,
+        key_size: 1,
+        value_size: 1,
+        key: b"a".to_vec(),
+        value: b"x".to_vec(),
+    }).unwrap();
+    DataFile::new(&path, merge_id + 1, Some(0)).unwrap();
+
+    let bitcask = Bitcask::new(path.clone(), BitcaskOptions::default()).unwrap();
+    assert_eq!(b"1".to_vec(), bitcask.get("a").unwrap().unwrap());
+    assert_eq!(b"2".to_vec(), bitcask.get("b").unwrap().unwrap());
+    assert!(!Path::new(&path).join(format!("{}.data", merge_id)).exists());
+    assert!(!Path::new(&path).join(format!("{}.data", merge_id + 1)).exists());
+    assert!(Manifest::load(&path).unwrap().unwrap().merging.is_empty());
+}
+
+#[test]
+fn test_range_and_prefix() {
+    for &ordered in &[false, true] {
+        let mut option = BitcaskOptions::default();
+        option.ordered_keydir = ordered;
+        let dir = test_dir(&format!("range_{}", ordered));
+        let mut bitcask = Bitcask::new(dir.path(), option).unwrap();
+        for key in &["user:2:session", "user:1:session", "user:1:name", "user;", "a"] {
+            bitcask.put(key, key.as_bytes().to_vec()).unwrap();
+        }
+        bitcask.delete("user:1:name").unwrap();
+
+        let keys = bitcask.prefix("user:").keys().collect::<Vec<Vec<u8>>>();
+        assert_eq!(keys, vec![b"user:1:session".to_vec(), b"user:2:session".to_vec()]);
+
+        let pairs = bitcask.range("a".."user:2").collect::<Result<Vec<(Vec<u8>, Vec<u8>)>>>().unwrap();
+        assert_eq!(pairs, vec![(b"a".to_vec(), b"a".to_vec()),
+                               (b"user:1:session".to_vec(), b"user:1:session".to_vec())]);
+        assert_eq!(5 - 1, bitcask.range::<&str, _>(..).count());
+    }
+}
+
+#[test]
+fn test_iteration() {
+    let dir = test_dir("iteration");
+    let mut bitcask = Bitcask::new(dir.path(), BitcaskOptions::default()).unwrap();
+    bitcask.put("a", b"1".to_vec()).unwrap();
+    bitcask.put("b", b"22".to_vec()).unwrap();
+    bitcask.put("c", b"333".to_vec()).unwrap();
+    bitcask.delete("b").unwrap();
+
+    let mut keys = bitcask.keys().collect::<Vec<Vec<u8>>>();
+    keys.sort();
+    assert_eq!(keys, vec![b"a".to_vec(), b"c".to_vec()]);
+
+    let mut pairs = bitcask.iter().collect::<Result<Vec<(Vec<u8>, Vec<u8>)>>>().unwrap();
+    pairs.sort();
+    assert_eq!(pairs, vec![(b"a".to_vec(), b"1".to_vec()), (b"c".to_vec(), b"333".to_vec())]);
+
+    let total = bitcask.fold(0, |acc, _, value| acc + value.len()).unwrap();
+    assert_eq!(total, 4);
+}
+
+#[test]
+fn test_snapshot() {
+    let mut option = BitcaskOptions::default();
+    option.file_size_limit = 64;
+    let dir = test_dir("snapshot");
+    let mut bitcask = Bitcask::new(dir.path(), option).unwrap();
+    bitcask.put("a", b"1".to_vec()).unwrap();
+    bitcask.put("b", b"2".to_vec()).unwrap();
+
+    let snapshot = bitcask.snapshot();
+    bitcask.put("a", b"3".to_vec()).unwrap();
+    bitcask.delete("b").unwrap();
+    for i in 0..10 {
+        bitcask.put(format!("key{}", i), vec![b'v'; 20]).unwrap();
+    }
+
+    assert_eq!(b"1".to_vec(), snapshot.get("a").unwrap().unwrap());
+    assert_eq!(b"2".to_vec(), snapshot.get("b").unwrap().unwrap());
+    assert_eq!(None, snapshot.get("key0").unwrap());
+    assert_eq!(2, snapshot.iter().count());
+    assert_eq!(b"3".to_vec(), bitcask.get("a").unwrap().unwrap());
+    assert_eq!(None, bitcask.get("b").unwrap());
+
+    // A keydir entry without its data file is an error, not a missing key.
+    let mut entries = KeyDir::new(false);
+    entries.insert(b"a".to_vec(), Entry { timestamp: 0, flags: 0, value_size: 1, value_pos: 0, file_id: 7 });
+    let snapshot = Snapshot::new(Arc::new(entries), HashMap::new(), None);
+    match snapshot.get("a") {
+        Err(e) => match *e.kind() {
+            ErrorKind::MissingFile(7) => (),
+            _ => panic!("unexpected error {:?}", e),
+        },
+        Ok(v) => panic!("missing file read as {:?}", v),
+    }
+}
+
+#[test]
+fn test_compression() {
+    let dir = test_dir("compression");
+    let path = dir.path();
+    let json = b"{\"user\": \"bitcask\", \"tags\": [\"a\", \"a\", \"a\", \"a\", \"a\", \"a\"]}".to_vec();
+    let mut bitcask = Bitcask::new(path.clone(), BitcaskOptions::default()).unwrap();
+    bitcask.put("plain", json.clone()).unwrap();
+    drop(bitcask);
+
+    for &codec in &[Compression::Lz4, Compression::Zstd, Compression::Snappy] {
+        let mut option = BitcaskOptions::default();
+        option.compression = codec;
+        option.compression_threshold = 16;
+        let mut bitcask = Bitcask::new(path.clone(), option).unwrap();
+        bitcask.put("json", json.clone()).unwrap();
+        bitcask.put("short", b"tiny".to_vec()).unwrap();
+        assert!(bitcask.entries.get(b"json").unwrap().value_size < json.len() as u64);
+        assert_eq!(0, bitcask.entries.get(b"short").unwrap().flags);
+        assert_eq!(json, bitcask.get("json").unwrap().unwrap());
+        assert_eq!(json, bitcask.get("plain").unwrap().unwrap());
+        drop(bitcask);
+
+        let bitcask = Bitcask::new(path.clone(), BitcaskOptions::default()).unwrap();
+        assert_eq!(json, bitcask.get("json").unwrap().unwrap());
+        assert_eq!(b"tiny".to_vec(), bitcask.get("short").unwrap().unwrap());
+    }
+}
+
+#[test]
+fn test_encryption() {
+    use std::io::Read;
+    use std::io::Write;
+
+    let dir = test_dir("encryption");
+    let path = dir.path();
+    let key_file = Path::new(&path).join("keys");
+    ::std::fs::File::create(&key_file).unwrap().write_all(format!("1 {}\n", "ab".repeat(32)).as_bytes()).unwrap();
+    let mut option = BitcaskOptions::default();
+    option.key_file = Some(key_file.clone());
+    let mut bitcask = Bitcask::new(path.clone(), option).unwrap();
+    bitcask.put("secret-key", b"secret-value".to_vec()).unwrap();
+    bitcask.put("gone", b"x".to_vec()).unwrap();
+    bitcask.delete("gone").unwrap();
+    assert_eq!(b"secret-value".to_vec(), bitcask.get("secret-key").unwrap().unwrap());
+    drop(bitcask);
+
+    for ext in &["data", "hint"] {
+        let mut contents = Vec::new();
+        ::std::fs::File::open(Path::new(&path).join(format!("0.{}", ext))).unwrap().read_to_end(&mut contents).unwrap();
+        assert!(!contents.windows(6).any(|w| w == b"secret"));
+    }
+    assert!(Bitcask::new(path.clone(), BitcaskOptions::default()).is_err());
+
+    // Rotated: new records use key 2, old ones still open with key 1.
+    ::std::fs::File::create(&key_file).unwrap()
+        .write_all(format!("1 {}\n2 {}\n", "ab".repeat(32), "cd".repeat(32)).as_bytes()).unwrap();
+    let mut option = BitcaskOptions::default();
+    option.key_file = Some(key_file.clone());
+    let mut bitcask = Bitcask::new(path.clone(), option).unwrap();
+    bitcask.put("new", b"value".to_vec()).unwrap();
+    assert_eq!(b"secret-value".to_vec(), bitcask.get("secret-key").unwrap().unwrap());
+    assert_eq!(b"value".to_vec(), bitcask.get("new").unwrap().unwrap());
+    assert_eq!(None, bitcask.get("gone").unwrap());
+}
+
+#[test]
+fn test_checkpoint() {
+    use std::os::unix::fs::MetadataExt;
+
+    let mut option = BitcaskOptions::default();
+    option.file_size_limit = 64;
+    let dir = test_dir("checkpoint");
+    let mut bitcask = Bitcask::new(dir.path(), option).unwrap();
+    for i in 0..5 {
+        bitcask.put(format!("key{}", i), vec![b'v'; 20]).unwrap();
+    }
+    bitcask.put("active", b"1".to_vec()).unwrap();
+
+    let dest_dir = test_dir("checkpoint_dest");
+    let dest = dest_dir.0.join("backup");
+    bitcask.checkpoint(&dest).unwrap();
+    bitcask.put("active", b"2".to_vec()).unwrap();
+    bitcask.delete("key0").unwrap();
+    assert!(bitcask.checkpoint(&dest).is_err());
+
+    assert_eq!(2, fs::metadata(dest.join("0.data")).unwrap().nlink());
+    let backup = Bitcask::new(dest.to_string_lossy().into_owned(), BitcaskOptions::default()).unwrap();
+    assert_eq!(b"1".to_vec(), backup.get("active").unwrap().unwrap());
+    assert_eq!(vec![b'v'; 20], backup.get("key0").unwrap().unwrap());
+    assert_eq!(6, backup.keys().count());
+}
+
+#[test]
+fn test_file_stats() {
+    let dir = test_dir("file_stats");
+    let mut bitcask = Bitcask::new(dir.path(), BitcaskOptions::default()).unwrap();
+    bitcask.put("a", b"1".to_vec()).unwrap();
+    bitcask.put("b", b"2".to_vec()).unwrap();
+    bitcask.put("a", b"3".to_vec()).unwrap();
+    bitcask.delete("b").unwrap();
+
+    // 13 bytes a record, 16 for the tombstone.
+    let stats = bitcask.file_stats();
+    assert_eq!(stats, vec![FileStats { file_id: 0, live_keys: 1, live_bytes: 13, dead_keys: 3, dead_bytes: 42 }]);
+    assert_eq!(76, stats[0].fragmentation());
+    drop(bitcask);
+
+    // Rebuilt from the hints.
+    let mut option = BitcaskOptions::default();
+    option.file_size_limit = 0;
+    let mut bitcask = Bitcask::new(dir.path(), option).unwrap();
+    assert_eq!(stats, bitcask.file_stats());
+    // Written to file 0, which is then sealed.
+    bitcask.put("a", b"4".to_vec()).unwrap();
+    bitcask.put("a", b"5".to_vec()).unwrap();
+    assert_eq!(bitcask.file_stats(), vec![
+        FileStats { file_id: 0, live_keys: 0, live_bytes: 0, dead_keys: 5, dead_bytes: 68 },
+        FileStats { file_id: 1, live_keys: 1, live_bytes: 13, dead_keys: 0, dead_bytes: 0 },
+        FileStats::new(2),
+    ]);
+}
+
+#[test]
+fn test_export_and_import() {
+    let dir = test_dir("export");
+    let mut bitcask = Bitcask::new(dir.path(), BitcaskOptions::default()).unwrap();
+    bitcask.put("a", b"1".to_vec()).unwrap();
+    bitcask.put(vec![0xff], vec![0; 300]).unwrap();
+    bitcask.put("b", b"2".to_vec()).unwrap();
+    bitcask.delete("b").unwrap();
+
+    for &format in &[ExportFormat::Json, ExportFormat::Binary] {
+        let mut buf = Vec::new();
+        assert_eq!(2, bitcask.export(&mut buf, format).unwrap());
+
+        let mut option = BitcaskOptions::default();
+        option.compression = Compression::Lz4;
+        let import_dir = test_dir(&format!("import_{:?}", format));
+        let mut copy = Bitcask::new(import_dir.path(), option).unwrap();
+        assert_eq!(2, copy.import(buf.as_slice(), format, 1).unwrap());
+        assert_eq!(b"1".to_vec(), copy.get("a").unwrap().unwrap());
+        assert_eq!(vec![0; 300], copy.get(&[0xff]).unwrap().unwrap());
+        assert_eq!(2, copy.keys().count());
+    }
+
+    let json = "{\"bitcask_export\":1}\n{\"key\":\"Yw==\",\"value\":\"Mw==\",\"ts\":7,\"flags\":1,\"ttl\":60}\n";
+    assert_eq!(1, bitcask.import(json.as_bytes(), ExportFormat::Json, 100).unwrap());
+    assert_eq!(b"3".to_vec(), bitcask.get("c").unwrap().unwrap());
+    let mut buf = Vec::new();
+    bitcask.export(&mut buf, ExportFormat::Json).unwrap();
+    assert!(String::from_utf8(buf).unwrap().contains("{\"key\":\"Yw==\",\"value\":\"Mw==\",\"ts\":7,\"flags\":0,\"ttl\":0}\n"));
+}
+
+#[test]
+fn test_merge() {
+    use bitcask::merge::MergeJob;
+
+    let dir = test_dir("merge");
+    let path = dir.path();
+    let mut option = BitcaskOptions::default();
+    // Every write seals its file.
+    option.file_size_limit = 0;
+    option.merge_fragmentation = 60;
+    option.merge_small_file_size = 0;
+    let mut bitcask = Bitcask::new(path.clone(), option.clone()).unwrap();
+    bitcask.write_batch(vec![WriteOp::Put(b"a".to_vec(), b"1".to_vec()), WriteOp::Put(b"c".to_vec(), b"1".to_vec())]).unwrap();
+    bitcask.delete("a").unwrap();
+    bitcask.put("b", vec![b'2'; 100]).unwrap();
+    let snapshot = bitcask.snapshot();
+    bitcask.put("b", vec![b'3'; 100]).unwrap();
+
+    // File 0 is only half dead and left alone, so the tombstone of file 1
+    // is kept to delete "a" in it.
+    let mut job = bitcask.start_merge(false).unwrap().unwrap();
+    assert_eq!(vec![1, 2], job.inputs());
+    assert!(bitcask.start_merge(false).unwrap().is_none());
+    {
+        let db = &mut bitcask;
+        job.run(&mut || db.reserve_merge_file()).unwrap();
+    }
+    let stats = bitcask.merge_stats();
+    assert!(stats.running);
+    assert_eq!(stats.bytes_to_read, stats.bytes_read);
+    bitcask.finish_merge(job).unwrap();
+    let stats = bitcask.merge_stats();
+    assert!(!stats.running);
+    assert_eq!((1, 2, 112), (stats.merges, stats.files_merged, stats.bytes_reclaimed));
+    assert_eq!(vec![0, 3, 4, 5], bitcask.file_stats().iter().map(|s| s.file_id).collect::<Vec<u32>>());
+    assert_eq!(FileStats { file_id: 5, live_keys: 0, live_bytes: 0, dead_keys: 1, dead_bytes: 16 }, bitcask.file_stats()[3]);
+    // Pinned by the snapshot until it is dropped.
+    assert!(Path::new(&path).join("2.data").exists());
+    assert_eq!(vec![b'2'; 100], snapshot.get("b").unwrap().unwrap());
+    drop(snapshot);
+    assert!(!Path::new(&path).join("2.data").exists());
+    assert!(!Path::new(&path).join("2.hint").exists());
+    drop(bitcask);
+
+    let mut option = option.clone();
+    option.compression = Compression::Lz4;
+    option.compression_threshold = 0;
+    let mut bitcask = Bitcask::new(path.clone(), option).unwrap();
+    assert!(!Path::new(&path).join("2.data").exists());
+    assert_eq!(None, bitcask.get("a").unwrap());
+    assert_eq!(vec![b'3'; 100], bitcask.get("b").unwrap().unwrap());
+
+    // Writes made while a merge runs win over what it copied.
+    let mut job: MergeJob = bitcask.start_merge(true).unwrap().unwrap();
+    {
+        let db = &mut bitcask;
+        job.run(&mut || db.reserve_merge_file()).unwrap();
+    }
+    let outputs = job.outputs().to_vec();
+    bitcask.put("c", b"4".to_vec()).unwrap();
+    bitcask.finish_merge(job).unwrap();
+    assert_eq!(b"4".to_vec(), bitcask.get("c").unwrap().unwrap());
+    // Nothing is left before the outputs, so the tombstone was dropped.
+    let hints = outputs.iter()
+        .flat_map(|&file_id| HintFile::new(&path, file_id, None).unwrap())
+        .collect::<Vec<HintEntry>>();
+    assert_eq!(2, hints.len());
+    // "c" is too short to shrink, "b" is recompressed.
+    assert_eq!(vec![0, Compression::Lz4.flags()], hints.iter().map(|hint| hint.flags).collect::<Vec<u8>>());
+    drop(bitcask);
+
+    let mut bitcask = Bitcask::new(path.clone(), BitcaskOptions::default()).unwrap();
+    assert_eq!(None, bitcask.get("a").unwrap());
+    assert_eq!(vec![b'3'; 100], bitcask.get("b").unwrap().unwrap());
+    assert_eq!(b"4".to_vec(), bitcask.get("c").unwrap().unwrap());
+    bitcask.merge().unwrap();
+    assert_eq!(vec![b'3'; 100], bitcask.get("b").unwrap().unwrap());
+}
+
+#[test]
+fn test_compaction_filter() {
+    use bitcask::filter::FilterDecision;
+    use bitcask::filter::RecordMetadata;
+
+    struct Migrate;
+
+    impl CompactionFilter for Migrate {
+        fn filter(&self, key: &[u8], value: &[u8], metadata: &RecordMetadata) -> FilterDecision {
+            assert!(metadata.timestamp > 0);
+            if key.starts_with(b"old:") {
+                FilterDecision::Remove
+            } else if value == b"v1" {
+                FilterDecision::Replace(b"v2".to_vec())
+            } else {
+                FilterDecision::Keep
+            }
+        }
+    }
+
+    let dir = test_dir("compaction_filter");
+    let path = dir.path();
+    let mut option = BitcaskOptions::default();
+    option.file_size_limit = 0;
+    option.compaction_filter = Some(Arc::new(Migrate));
+    let mut bitcask = Bitcask::new(path.clone(), option).unwrap();
+    bitcask.put("old:a", b"1".to_vec()).unwrap();
+    bitcask.put("b", b"v1".to_vec()).unwrap();
+    bitcask.put("c", b"3".to_vec()).unwrap();
+    bitcask.merge().unwrap();
+    assert_eq!(None, bitcask.get("old:a").unwrap());
+    assert_eq!(b"v2".to_vec(), bitcask.get("b").unwrap().unwrap());
+    assert_eq!(b"3".to_vec(), bitcask.get("c").unwrap().unwrap());
+    drop(bitcask);
+
+    let bitcask = Bitcask::new(path.clone(), BitcaskOptions::default()).unwrap();
+    assert_eq!(None, bitcask.get("old:a").unwrap());
+    assert_eq!(b"v2".to_vec(), bitcask.get("b").unwrap().unwrap());
+    assert_eq!(vec![b"b".to_vec(), b"c".to_vec()], {
+        let mut keys = bitcask.keys().collect::<Vec<Vec<u8>>>();
+        keys.sort();
+        keys
+    });
+}
+
+#[test]
+fn test_merge_concurrent_with_get() {
+    use std::sync::RwLock;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::atomic::Ordering;
+    use std::thread;
+
+    let dir = test_dir("merge_concurrent");
+    let path = dir.path();
+    let mut option = BitcaskOptions::default();
+    option.file_size_limit = 0;
+    let mut bitcask = Bitcask::new(path.clone(), option).unwrap();
+    for i in 0..50 {
+        bitcask.put(format!("key{}", i % 10), format!("value{}", i).into_bytes()).unwrap();
+    }
+    let iter = bitcask.iter();
+    let db = Arc::new(RwLock::new(bitcask));
+    let done = Arc::new(AtomicBool::new(false));
+    let merger = {
+        let db = db.clone();
+        let done = done.clone();
+        thread::spawn(move || {
+            let mut job = db.write().unwrap().start_merge(true).unwrap().unwrap();
+            job.run(&mut || db.write().unwrap().reserve_merge_file()).unwrap();
+            db.write().unwrap().finish_merge(job).unwrap();
+            done.store(true, Ordering::SeqCst);
+        })
+    };
+    loop {
+        let finished = done.load(Ordering::SeqCst);
+        for i in 40..50 {
+            let value = db.read().unwrap().get(format!("key{}", i % 10)).unwrap();
+            assert_eq!(Some(format!("value{}", i).into_bytes()), value);
+        }
+        if finished {
+            break;
+        }
+    }
+    merger.join().unwrap();
+
+    // The iterator still reads the merged files, which go with it.
+    let data_files = || ::std::fs::read_dir(&path).unwrap()
+        .filter(|entry| entry.as_ref().unwrap().path().extension().map_or(false, |ext| ext == "data"))
+        .count();
+    assert_eq!(50 + 1 + 10, data_files());
+    assert_eq!(10, iter.map(|item| item.unwrap()).filter(|&(_, ref value)| value.starts_with(b"value4")).count());
+    assert_eq!(10 + 1, data_files());
+}
+
+#[test]
+fn test_merge_damaged_hints() {
+    use std::fs::OpenOptions;
+    use std::io::Seek;
+    use std::io::SeekFrom;
+
+    let dir = test_dir("merge_damaged_hints");
+    let path = dir.path();
+    let mut option = BitcaskOptions::default();
+    option.file_size_limit = 0;
+    let mut bitcask = Bitcask::new(path.clone(), option).unwrap();
+    bitcask.write_batch(vec![
+        WriteOp::Put(b"a".to_vec(), b"1".to_vec()),
+        WriteOp::Put(b"b".to_vec(), b"2".to_vec()),
+        WriteOp::Put(b"c".to_vec(), b"3".to_vec()),
+    ]).unwrap();
+    bitcask.put("a", b"4".to_vec()).unwrap();
+    let hint_path = Path::new(&path).join("0.hint");
+
+    // The middle entry of 0.hint, each 20 bytes after the header, is
+    // corrupt: the merge fails rather than skip the rest.
+    let mut hint = OpenOptions::new().write(true).open(&hint_path).unwrap();
+    hint.seek(SeekFrom::Start(16 + 20 + 10)).unwrap();
+    hint.write_all(&[0xff]).unwrap();
+    assert!(bitcask.merge().is_err());
+    assert!(!bitcask.merge_stats().running);
+
+    // Cut off before "c", which the keydir still points at in 0.data.
+    hint.set_len(16 + 20).unwrap();
+    assert!(bitcask.merge().is_err());
+    assert!(Path::new(&path).join("0.data").exists());
+    assert_eq!(b"2".to_vec(), bitcask.get("b").unwrap().unwrap());
+    assert_eq!(b"3".to_vec(), bitcask.get("c").unwrap().unwrap());
+    assert!(Manifest::load(&path).unwrap().unwrap().merging.is_empty());
+}
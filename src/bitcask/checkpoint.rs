@@ -0,0 +1,60 @@
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+
+use bitcask::manifest::Manifest;
+use ::error::Result;
+
+
+/// Makes `dest` a copy of the store in `path` that `Bitcask::new` can open.
+/// Sealed files never change, so they are hard-linked, or copied when `dest`
+/// is on another filesystem. The active data and hint files are copied up to
+/// `active_lens`, where the writer left them after its last complete write.
+/// The manifest is written last, so an interrupted checkpoint is recognizably
+/// incomplete.
+pub fn checkpoint(path: &Path, dest: &Path, manifest: &Manifest, active_lens: (u64, u64)) -> Result<()> {
+    try!(fs::create_dir_all(dest));
+    if try!(dest.read_dir()).next().is_some() {
+        return Err(format!("checkpoint directory {} is not empty", dest.display()).into());
+    }
+
+    for &file_id in manifest.sealed.iter() {
+        for ext in &["data", "hint"] {
+            let name = format!("{}.{}", file_id, ext);
+            if !path.join(&name).exists() {
+                continue;
+            }
+            if fs::hard_link(path.join(&name), dest.join(&name)).is_err() {
+                try!(copy_prefix(&path.join(&name), &dest.join(&name), None));
+            }
+        }
+    }
+
+    let (data_len, hint_len) = active_lens;
+    try!(copy_prefix(&path.join(format!("{}.data", manifest.active)),
+                     &dest.join(format!("{}.data", manifest.active)), Some(data_len)));
+    try!(copy_prefix(&path.join(format!("{}.hint", manifest.active)),
+                     &dest.join(format!("{}.hint", manifest.active)), Some(hint_len)));
+
+    Manifest {
+        active: manifest.active,
+        sealed: manifest.sealed.clone(),
+        merging: Default::default(),
+    }.store(dest)
+}
+
+
+/// Copies the first `len` bytes of `from`, or all of it, and syncs the copy.
+fn copy_prefix(from: &Path, to: &Path, len: Option<u64>) -> Result<()> {
+    let source = try!(File::open(from));
+    let mut target = try!(OpenOptions::new().write(true).create_new(true).open(to));
+    match len {
+        Some(len) => try!(io::copy(&mut source.take(len), &mut target)),
+        None => try!(io::copy(&mut &source, &mut target)),
+    };
+    try!(target.sync_all());
+    Ok(())
+}
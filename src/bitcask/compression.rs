@@ -0,0 +1,99 @@
+use std::str::FromStr;
+
+use lz4_flex;
+use snap;
+use zstd;
+
+use ::error::ErrorKind;
+use ::error::Result;
+
+
+/// Bits of a record's flags that hold the codec of its value.
+pub const CODEC_MASK: u8 = 0x03;
+const ZSTD_LEVEL: i32 = 3;
+
+
+/// How values are compressed on disk. Each record carries the codec it was
+/// written with, so changing this only affects new writes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    None,
+    Lz4,
+    Zstd,
+    Snappy,
+}
+
+
+impl Compression {
+    /// The codec of a record with `flags`.
+    pub fn from_flags(flags: u8) -> Compression {
+        match flags & CODEC_MASK {
+            0 => Compression::None,
+            1 => Compression::Lz4,
+            2 => Compression::Zstd,
+            _ => Compression::Snappy,
+        }
+    }
+
+    /// The flag bits for the codec.
+    pub fn flags(&self) -> u8 {
+        match *self {
+            Compression::None => 0,
+            Compression::Lz4 => 1,
+            Compression::Zstd => 2,
+            Compression::Snappy => 3,
+        }
+    }
+
+    pub fn compress(&self, value: &[u8]) -> Result<Vec<u8>> {
+        Ok(match *self {
+            Compression::None => value.to_vec(),
+            Compression::Lz4 => lz4_flex::block::compress_prepend_size(value),
+            Compression::Zstd => try!(zstd::bulk::compress(value, ZSTD_LEVEL)),
+            Compression::Snappy => try!(snap::raw::Encoder::new().compress_vec(value)
+                .map_err(|e| ErrorKind::Compression(format!("{}", e)))),
+        })
+    }
+
+    pub fn decompress(&self, value: Vec<u8>) -> Result<Vec<u8>> {
+        Ok(match *self {
+            Compression::None => value,
+            Compression::Lz4 => try!(lz4_flex::block::decompress_size_prepended(&value)
+                .map_err(|e| ErrorKind::Compression(format!("{}", e)))),
+            Compression::Zstd => try!(zstd::stream::decode_all(value.as_slice())),
+            Compression::Snappy => try!(snap::raw::Decoder::new().decompress_vec(&value)
+                .map_err(|e| ErrorKind::Compression(format!("{}", e)))),
+        })
+    }
+}
+
+
+impl FromStr for Compression {
+    type Err = ::error::Error;
+
+    fn from_str(s: &str) -> Result<Compression> {
+        match s {
+            "none" => Ok(Compression::None),
+            "lz4" => Ok(Compression::Lz4),
+            "zstd" => Ok(Compression::Zstd),
+            "snappy" => Ok(Compression::Snappy),
+            _ => Err(format!("unknown compression {}", s).into()),
+        }
+    }
+}
+
+
+#[test]
+fn test_round_trip() {
+    let value = b"{\"name\": \"bitcask\", \"name\": \"bitcask\", \"name\": \"bitcask\"}".to_vec();
+    for &codec in &[Compression::None, Compression::Lz4, Compression::Zstd, Compression::Snappy] {
+        let compressed = codec.compress(&value).unwrap();
+        if codec != Compression::None {
+            assert!(compressed.len() < value.len());
+        }
+        assert_eq!(Compression::from_flags(codec.flags()), codec);
+        assert_eq!(codec.decompress(compressed).unwrap(), value);
+    }
+    assert!(Compression::Lz4.decompress(vec![0xff; 8]).is_err());
+    assert_eq!("zstd".parse::<Compression>().unwrap(), Compression::Zstd);
+}
@@ -0,0 +1,82 @@
+use std::io;
+use std::io::Read;
+
+
+const fn make_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+const TABLE: [u32; 256] = make_table();
+
+
+/// CRC-32 (IEEE), as used by zlib and Ethernet.
+pub struct Crc32(u32);
+
+
+impl Crc32 {
+    pub fn new() -> Crc32 {
+        Crc32(!0)
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = TABLE[((self.0 ^ byte as u32) & 0xff) as usize] ^ (self.0 >> 8);
+        }
+    }
+
+    pub fn finish(&self) -> u32 {
+        !self.0
+    }
+}
+
+
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(bytes);
+    crc.finish()
+}
+
+
+/// Checksums everything read through it.
+pub struct CrcReader<R> {
+    inner: R,
+    pub crc: Crc32,
+}
+
+
+impl<R: Read> CrcReader<R> {
+    pub fn new(inner: R) -> CrcReader<R> {
+        CrcReader {
+            inner: inner,
+            crc: Crc32::new(),
+        }
+    }
+}
+
+
+impl<R: Read> Read for CrcReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = try!(self.inner.read(buf));
+        self.crc.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+
+#[test]
+fn test_crc32() {
+    assert_eq!(crc32(b""), 0);
+    assert_eq!(crc32(b"123456789"), 0xcbf43926);
+}
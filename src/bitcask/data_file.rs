@@ -1,132 +1,251 @@
 use std;
+use std::fs;
 use std::fs::File;
 use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
 use std::fs::OpenOptions;
 use std::io::Seek;
+use std::os::unix::fs::FileExt;
 use std::io::{Read, Write};
 use byteorder::ReadBytesExt;
 use byteorder::WriteBytesExt;
 use byteorder::LittleEndian;
 
+use bitcask::header::FileHeader;
+use bitcask::crc::CrcReader;
+use bitcask::crc::crc32;
+use bitcask::header::DATA_MAGIC;
+use bitcask::varint::read_varint;
+use bitcask::varint::varint_len;
+use bitcask::varint::write_varint;
+use ::error::Result;
 
+
+/// Record flag of a delete. Its value is never read.
+pub const TOMBSTONE: u8 = 0x08;
+
+
+/// A data file, shared between the store, snapshots and merges as an
+/// `Arc<DataFile>`. Once a merge replaces it, it is marked with
+/// `remove_on_drop`, and whoever drops the last handle removes it along
+/// with its hint file.
 #[derive(Debug)]
 pub struct DataFile {
     file: File,
     pub file_id: u32,
+    pub header: FileHeader,
     write_offset: Option<u64>,
+    dir: PathBuf,
+    obsolete: AtomicBool,
 }
 
 
 #[derive(Debug)]
 pub struct DataEntry {
-    pub crc: u16,
+    pub crc: u32,
     pub timestamp: u32,
-    pub key_size: u8,
-    pub value_size: u32,
+    /// Bits 0-1 hold the codec of the value (`compression::CODEC_MASK`),
+    /// bit 2 is `encryption::ENCRYPTED` and bit 3 `TOMBSTONE`. The rest are
+    /// zero. Hint and keydir entries carry the same byte.
+    pub flags: u8,
+    pub key_size: u32,
+    pub value_size: u64,
     pub key: Vec<u8>,
     pub value: Vec<u8>,
 }
 
 
 impl DataFile {
-    pub fn new<P: AsRef<Path>>(path: P, file_id: u32, write_offset: Option<u64>) -> DataFile {
+    pub fn new<P: AsRef<Path>>(path: P, file_id: u32, write_offset: Option<u64>) -> Result<DataFile> {
         let file_path = path.as_ref().join(format!("{}.data", file_id));
         let mut open_options = OpenOptions::new();
         open_options.read(true);
-        let mut file = match write_offset {
+        let mut file = try!(match write_offset {
             None => open_options.open(&file_path),
             Some(_) => {
                 open_options.create(true).append(true).open(&file_path)
             }
-        }.expect(&format!("open data file {}/{}", path.as_ref().to_string_lossy(), file_id));
+        });
+        let header = try!(FileHeader::init(&mut file, &file_path, DATA_MAGIC, file_id, write_offset.is_some()));
 
         let new_offset = if write_offset.is_some() {
-            Some(file.seek(std::io::SeekFrom::Current(0)).expect("seek file end"))
+            Some(try!(file.seek(std::io::SeekFrom::End(0))))
         } else {
             write_offset
         };
 
-        DataFile {
+        Ok(DataFile {
             file: file,
             file_id: file_id,
+            header: header,
             write_offset: new_offset,
-        }
+            dir: path.as_ref().to_path_buf(),
+            obsolete: AtomicBool::new(false),
+        })
+    }
+
+    /// Removes the data and hint files once this handle is dropped.
+    pub fn remove_on_drop(&self) {
+        self.obsolete.store(true, Ordering::SeqCst);
     }
 
     fn is_readonly(&self) -> bool {
         return self.write_offset.is_none()
     }
 
-    pub fn read_exact(&mut self, value_offse: u64, value: &mut [u8]) -> Result<(), String> {
-        if let Err(_) = self.file.seek(std::io::SeekFrom::Start(value_offse)) {
-            panic!("seek")
-        }
+    /// Flushes what was written to disk.
+    pub fn sync(&self) -> Result<()> {
+        try!(self.file.sync_all());
+        Ok(())
+    }
 
-        self.file.read_exact(value).expect("read value");
+    /// Reads at `value_offse` without moving the file position, so that
+    /// any number of readers can share the file.
+    pub fn read_exact(&self, value_offse: u64, value: &mut [u8]) -> Result<()> {
+        try!(self.file.read_exact_at(value, value_offse));
 
         Ok(())
     }
 
-    pub fn write(&mut self, data_entry: &DataEntry) -> Result<u64, String> {
+    pub fn write(&mut self, data_entry: &DataEntry) -> Result<u64> {
+        let positions = try!(self.write_entries(std::slice::from_ref(data_entry)));
+        Ok(positions[0])
+    }
+
+    /// Appends all entries with a single write and returns the value
+    /// position of each one.
+    pub fn write_entries(&mut self, data_entries: &[DataEntry]) -> Result<Vec<u64>> {
         if self.is_readonly() {
-            unimplemented!()
+            return Err(format!("data file {} is read only", self.file_id).into());
         }
 
-        if let Err(_) = self.file.seek(std::io::SeekFrom::End(0)) {
-            panic!("seek end")
+        let offset = try!(self.file.seek(std::io::SeekFrom::End(0)));
+        let mut buf = Vec::new();
+        let mut positions = Vec::with_capacity(data_entries.len());
+        for data_entry in data_entries {
+            try!(data_entry.encode(&mut buf));
+            positions.push(offset + (buf.len() - data_entry.value.len()) as u64);
         }
-        self.file.write_u16::<LittleEndian>(data_entry.crc).expect("write crc");
-        self.file.write_u32::<LittleEndian>(data_entry.timestamp).expect("write timestamp");
-        self.file.write(&[data_entry.key_size]).expect("write key size");
-        self.file.write_u32::<LittleEndian>(data_entry.value_size).expect("write value size");
-        self.file.write_all(&data_entry.key).expect("write key");
-        let value_pos = self.file.seek(std::io::SeekFrom::Current(0)).expect("seek current position");
-        self.file.write_all(data_entry.value.as_slice()).expect("write value");
-        self.file.flush().expect("flush");
+        try!(self.file.write_all(&buf));
+        try!(self.file.flush());
 
-        Ok(value_pos)
+        Ok(positions)
     }
 }
 
 
-impl Iterator for DataFile {
-    type Item = DataEntry;
-    fn next(&mut self) -> Option<DataEntry> {
-        let crc = self.file.read_u16::<LittleEndian>().expect("read crc");
-        let timestamp = self.file.read_u32::<LittleEndian>().expect("read timestamp");
-        let mut buf = [0; 1];
-        self.file.read(&mut buf).expect("read key size");
-        let key_size = buf[0];
-        let value_size = self.file.read_u32::<LittleEndian>().expect("read value size");
-
-        let mut key = vec![0; key_size as usize];
-        self.file.read_exact(&mut key).expect("read key");
-        let mut value = vec![0; value_size as usize];
-        self.file.read_exact(&mut value).expect("read value");
-
-        Some(DataEntry {
+impl DataEntry {
+    /// Appends the on-disk representation of the entry to `buf`. The
+    /// checksum is computed here, `crc` is only filled in by `decode`.
+    pub fn encode(&self, buf: &mut Vec<u8>) -> Result<()> {
+        let start = buf.len();
+        try!(buf.write_u32::<LittleEndian>(0));
+        try!(buf.write_u32::<LittleEndian>(self.timestamp));
+        buf.push(self.flags);
+        write_varint(buf, self.key_size as u64);
+        write_varint(buf, self.value_size);
+        buf.extend_from_slice(&self.key);
+        buf.extend_from_slice(&self.value);
+        let crc = crc32(&buf[start + 4..]);
+        try!((&mut buf[start..start + 4]).write_u32::<LittleEndian>(crc));
+        Ok(())
+    }
+
+    /// Reads an entry, failing with `InvalidData` if its checksum doesn't
+    /// match.
+    pub fn decode<R: Read>(reader: &mut R) -> std::io::Result<DataEntry> {
+        let crc = try!(reader.read_u32::<LittleEndian>());
+        let mut reader = CrcReader::new(reader);
+        let timestamp = try!(reader.read_u32::<LittleEndian>());
+        let flags = try!(reader.read_u8());
+        let key_size = try!(read_varint(&mut reader));
+        let value_size = try!(read_varint(&mut reader));
+        let key = try!(read_bytes(&mut reader, key_size));
+        let value = try!(read_bytes(&mut reader, value_size));
+        if reader.crc.finish() != crc {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "checksum mismatch"));
+        }
+
+        Ok(DataEntry {
             crc: crc,
             timestamp: timestamp,
-            key_size: key_size,
+            flags: flags,
+            key_size: key_size as u32,
             value_size: value_size,
             key: key,
             value: value,
         })
     }
+
+    /// Offset of the value from the start of the encoded entry.
+    pub fn value_offset(&self) -> u64 {
+        (4 + 4 + 1 + varint_len(self.key_size as u64) + varint_len(self.value_size)) as u64 + self.key_size as u64
+    }
+
+    pub fn encoded_len(&self) -> u64 {
+        self.value_offset() + self.value_size
+    }
+}
+
+
+/// Length of a data record with a key and value of these sizes.
+pub fn record_len(key_size: u64, value_size: u64) -> u64 {
+    (4 + 4 + 1 + varint_len(key_size) + varint_len(value_size)) as u64 + key_size + value_size
+}
+
+
+/// Reads exactly `size` bytes. The buffer grows as data arrives, so a
+/// corrupt size fails at the end of the file instead of allocating it.
+pub fn read_bytes<R: Read>(reader: &mut R, size: u64) -> std::io::Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(std::cmp::min(size, 64 * 1024) as usize);
+    try!(reader.take(size).read_to_end(&mut buf));
+    if (buf.len() as u64) < size {
+        return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "record truncated"));
+    }
+    Ok(buf)
+}
+
+
+impl Drop for DataFile {
+    fn drop(&mut self) {
+        if !self.obsolete.load(Ordering::SeqCst) {
+            return;
+        }
+        // The manifest no longer lists the file, so if it can't be removed
+        // now, the garbage collection of the next writable open removes it.
+        for ext in &["data", "hint"] {
+            let file_path = self.dir.join(format!("{}.{}", self.file_id, ext));
+            if let Err(e) = fs::remove_file(&file_path) {
+                eprintln!("remove {}: {:?}, left to the next open", file_path.display(), e);
+            }
+        }
+    }
+}
+
+
+impl Iterator for DataFile {
+    type Item = DataEntry;
+    fn next(&mut self) -> Option<DataEntry> {
+        DataEntry::decode(&mut self.file).ok()
+    }
 }
 
 
 #[test]
 fn test_write() {
+    let dir = ::bitcask::bitcask::test_dir("data-file");
     {
-        let mut db = DataFile::new(".".to_owned(), 10, Some(0));
+        let mut db = DataFile::new(dir.path(), 10, Some(0)).unwrap();
         let value = "你好".as_bytes().to_vec();
         let key = "哈哈".as_bytes().to_vec();
         let entry = DataEntry {
             crc: 1,
             timestamp: 1,
-            key_size: key.len() as u8,
-            value_size: value.len() as u32,
+            flags: 0,
+            key_size: key.len() as u32,
+            value_size: value.len() as u64,
             key: key,
             value: value,
         };
@@ -135,10 +254,11 @@ fn test_write() {
         assert!(db.write(&entry).is_ok());
     }
     {
-        let mut db = DataFile::new(".".to_owned(), 10, None);
+        let mut db = DataFile::new(dir.path(), 10, None).unwrap();
         let mut entry = DataEntry {
             crc: 1,
             timestamp: 1,
+            flags: 0,
             key_size: 2,
             value_size: 2,
             key: Vec::new(),
@@ -0,0 +1,153 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use byteorder::ByteOrder;
+use byteorder::LittleEndian;
+
+use chacha20poly1305::Key;
+use chacha20poly1305::KeyInit;
+use chacha20poly1305::XChaCha20Poly1305;
+use chacha20poly1305::XNonce;
+use chacha20poly1305::aead::Aead;
+use chacha20poly1305::aead::AeadCore;
+use chacha20poly1305::aead::OsRng;
+use chacha20poly1305::aead::Payload;
+
+use ::error::ErrorKind;
+use ::error::Result;
+
+
+/// Record flag of a record whose key and value are sealed by a `Keyring`.
+/// Tombstones keep their plain value.
+pub const ENCRYPTED: u8 = 0x04;
+const KEY_ID_SIZE: usize = 4;
+const NONCE_SIZE: usize = 24;
+const TAG_SIZE: usize = 16;
+
+
+/// The keys records are encrypted with, by id. New records are sealed with
+/// the highest id; older ids are kept to read what was written before a
+/// rotation, until a merge has rewritten it.
+///
+/// Keys are read from a key file with one `<id> <64 hex digits>` line per
+/// 256 bit key. Empty lines and lines starting with `#` are ignored.
+pub struct Keyring {
+    keys: BTreeMap<u32, XChaCha20Poly1305>,
+    current: u32,
+}
+
+
+impl Keyring {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Keyring> {
+        let mut contents = String::new();
+        try!(try!(File::open(path.as_ref())).read_to_string(&mut contents));
+        Keyring::parse(&contents)
+    }
+
+    pub fn parse(contents: &str) -> Result<Keyring> {
+        let mut keys = BTreeMap::new();
+        for line in contents.lines().map(|l| l.trim()) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut words = line.split_whitespace();
+            let id = try!(words.next().unwrap_or("").parse::<u32>());
+            let key = match words.next().and_then(decode_hex) {
+                Some(ref key) if key.len() == 32 => XChaCha20Poly1305::new(Key::from_slice(key)),
+                _ => return Err(ErrorKind::Encryption(format!("key {} is not 64 hex digits", id)).into()),
+            };
+            keys.insert(id, key);
+        }
+        let current = match keys.keys().next_back() {
+            Some(&id) => id,
+            None => return Err(ErrorKind::Encryption("no keys in the key file".to_owned()).into()),
+        };
+        Ok(Keyring {
+            keys: keys,
+            current: current,
+        })
+    }
+
+    /// The id new records are sealed with.
+    pub fn current(&self) -> u32 {
+        self.current
+    }
+
+    /// Encrypts `plaintext` with the current key, authenticating `aad` along
+    /// with it. The result starts with the key id and a random nonce.
+    pub fn seal(&self, plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = try!(self.keys[&self.current]
+            .encrypt(&nonce, Payload { msg: plaintext, aad: aad })
+            .map_err(|_| ErrorKind::Encryption("encryption failed".to_owned())));
+        let mut sealed = Vec::with_capacity(KEY_ID_SIZE + NONCE_SIZE + ciphertext.len());
+        sealed.extend_from_slice(&[0; KEY_ID_SIZE]);
+        LittleEndian::write_u32(&mut sealed[..KEY_ID_SIZE], self.current);
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    /// Decrypts what `seal` returned, with whichever key it was sealed with.
+    pub fn open(&self, sealed: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        let id = match key_id(sealed) {
+            Some(id) if sealed.len() >= KEY_ID_SIZE + NONCE_SIZE + TAG_SIZE => id,
+            _ => return Err(ErrorKind::Encryption("sealed data truncated".to_owned()).into()),
+        };
+        let key = match self.keys.get(&id) {
+            Some(key) => key,
+            None => return Err(ErrorKind::Encryption(format!("unknown key id {}", id)).into()),
+        };
+        let nonce = XNonce::from_slice(&sealed[KEY_ID_SIZE..KEY_ID_SIZE + NONCE_SIZE]);
+        let msg = &sealed[KEY_ID_SIZE + NONCE_SIZE..];
+        key.decrypt(nonce, Payload { msg: msg, aad: aad })
+            .map_err(|_| ErrorKind::Encryption(format!("authentication failed with key {}", id)).into())
+    }
+}
+
+
+/// Length of `plaintext_len` bytes once sealed.
+pub fn sealed_len(plaintext_len: usize) -> usize {
+    KEY_ID_SIZE + NONCE_SIZE + plaintext_len + TAG_SIZE
+}
+
+
+/// The id of the key `sealed` was sealed with.
+pub fn key_id(sealed: &[u8]) -> Option<u32> {
+    if sealed.len() < KEY_ID_SIZE {
+        return None;
+    }
+    Some(LittleEndian::read_u32(&sealed[..KEY_ID_SIZE]))
+}
+
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    (0..s.len()).step_by(2)
+        .map(|i| s.get(i..i + 2).and_then(|hex| u8::from_str_radix(hex, 16).ok()))
+        .collect()
+}
+
+
+#[test]
+fn test_seal_and_open() {
+    let old = Keyring::parse(&format!("1 {}\n", "11".repeat(32))).unwrap();
+    let keyring = Keyring::parse(&format!("# rotated\n1 {}\n2 {}\n", "11".repeat(32), "22".repeat(32))).unwrap();
+    assert_eq!(2, keyring.current());
+
+    let sealed = old.seal(b"value", b"key").unwrap();
+    assert_eq!(Some(1), key_id(&sealed));
+    assert_eq!(b"value".to_vec(), keyring.open(&sealed, b"key").unwrap());
+    assert!(keyring.open(&sealed, b"other key").is_err());
+
+    let sealed = keyring.seal(b"value", b"").unwrap();
+    assert_eq!(Some(2), key_id(&sealed));
+    assert_eq!(sealed_len(5), sealed.len());
+    assert!(old.open(&sealed, b"").is_err());
+
+    assert!(Keyring::parse("1 abcd\n").is_err());
+    assert!(Keyring::parse("# empty\n").is_err());
+}
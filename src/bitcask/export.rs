@@ -0,0 +1,273 @@
+use std::io::BufRead;
+use std::io::Write;
+use std::str::FromStr;
+use byteorder::LittleEndian;
+use byteorder::ReadBytesExt;
+use byteorder::WriteBytesExt;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde_json;
+use serde_json::Value;
+
+use bitcask::data_file::read_bytes;
+use bitcask::varint::read_varint;
+use bitcask::varint::write_varint;
+use ::error::ErrorKind;
+use ::error::Result;
+
+
+const EXPORT_VERSION: u64 = 1;
+const BINARY_MAGIC: [u8; 4] = *b"BCEX";
+
+
+/// Formats of `Bitcask::export`. Both are a header followed by a record per
+/// key, so they can be written and read as a stream.
+///
+/// `Json` is JSON Lines: a `{"bitcask_export":1}` line, then a line like
+/// `{"key":"a2V5","value":"dmFsdWU=","ts":1500000000,"flags":0,"ttl":0}` per
+/// key, with the key and value in base64. Blank lines are skipped.
+///
+/// `Binary` is the magic `BCEX` and a u16 version, then per key a u32
+/// timestamp, u32 flags and u32 ttl, the varint key and value sizes, the key
+/// and the value. Integers are little endian.
+///
+/// In both, `flags` and `ttl` are reserved: they are written as 0 and
+/// ignored on import, see `ExportRecord`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    Json,
+    Binary,
+}
+
+
+/// A key and its value as exported. `ts` is when it was written. `flags`
+/// and `ttl` are reserved for the memcached client flags and the seconds
+/// until the key expires, which the store doesn't keep yet.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExportRecord {
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+    pub timestamp: u32,
+    pub flags: u32,
+    pub ttl: u32,
+}
+
+
+/// Reads the records of an export, after checking its header.
+pub struct ExportReader<R: BufRead> {
+    reader: R,
+    format: ExportFormat,
+    record: u64,
+}
+
+
+pub fn write_header<W: Write>(out: &mut W, format: ExportFormat) -> Result<()> {
+    match format {
+        ExportFormat::Json => try!(writeln!(out, "{{\"bitcask_export\":{}}}", EXPORT_VERSION)),
+        ExportFormat::Binary => {
+            try!(out.write_all(&BINARY_MAGIC));
+            try!(out.write_u16::<LittleEndian>(EXPORT_VERSION as u16));
+        },
+    }
+    Ok(())
+}
+
+
+pub fn write_record<W: Write>(out: &mut W, format: ExportFormat, record: &ExportRecord) -> Result<()> {
+    match format {
+        ExportFormat::Json => {
+            try!(writeln!(out, "{{\"key\":\"{}\",\"value\":\"{}\",\"ts\":{},\"flags\":{},\"ttl\":{}}}",
+                          BASE64.encode(&record.key), BASE64.encode(&record.value),
+                          record.timestamp, record.flags, record.ttl));
+        },
+        ExportFormat::Binary => {
+            let mut buf = Vec::with_capacity(12 + 20 + record.key.len() + record.value.len());
+            try!(buf.write_u32::<LittleEndian>(record.timestamp));
+            try!(buf.write_u32::<LittleEndian>(record.flags));
+            try!(buf.write_u32::<LittleEndian>(record.ttl));
+            write_varint(&mut buf, record.key.len() as u64);
+            write_varint(&mut buf, record.value.len() as u64);
+            buf.extend_from_slice(&record.key);
+            buf.extend_from_slice(&record.value);
+            try!(out.write_all(&buf));
+        },
+    }
+    Ok(())
+}
+
+
+impl<R: BufRead> ExportReader<R> {
+    pub fn new(mut reader: R, format: ExportFormat) -> Result<ExportReader<R>> {
+        let version = match format {
+            ExportFormat::Json => {
+                let mut line = String::new();
+                try!(reader.read_line(&mut line));
+                serde_json::from_str::<Value>(&line).ok()
+                    .and_then(|header| header.get("bitcask_export").and_then(|v| v.as_u64()))
+            },
+            ExportFormat::Binary => {
+                let mut magic = [0; 4];
+                try!(reader.read_exact(&mut magic));
+                if magic == BINARY_MAGIC {
+                    Some(try!(reader.read_u16::<LittleEndian>()) as u64)
+                } else {
+                    None
+                }
+            },
+        };
+        match version {
+            Some(EXPORT_VERSION) => (),
+            Some(v) => return Err(ErrorKind::BadExport(0, format!("unsupported version {}", v)).into()),
+            None => return Err(ErrorKind::BadExport(0, format!("not a {:?} export", format)).into()),
+        }
+        Ok(ExportReader {
+            reader: reader,
+            format: format,
+            record: 0,
+        })
+    }
+
+    fn read_json(&mut self) -> Result<Option<ExportRecord>> {
+        let mut line = String::new();
+        loop {
+            if try!(self.reader.read_line(&mut line)) == 0 {
+                return Ok(None);
+            }
+            if !line.trim().is_empty() {
+                break;
+            }
+            line.clear();
+        }
+        let record = self.record;
+        let bad = |msg: String| ErrorKind::BadExport(record, msg);
+        let value = try!(serde_json::from_str::<Value>(&line).map_err(|e| bad(format!("{}", e))));
+        let bytes = |name: &str| match value.get(name).and_then(|v| v.as_str()) {
+            Some(s) => BASE64.decode(s).map_err(|e| bad(format!("{}: {}", name, e))),
+            None => Err(bad(format!("no {}", name))),
+        };
+        let number = |name: &str| match value.get(name) {
+            None => Ok(0),
+            Some(v) => match v.as_u64() {
+                Some(n) if n <= u32::max_value() as u64 => Ok(n as u32),
+                _ => Err(bad(format!("{} is not a u32", name))),
+            },
+        };
+        Ok(Some(ExportRecord {
+            key: try!(bytes("key")),
+            value: try!(bytes("value")),
+            timestamp: try!(number("ts")),
+            flags: try!(number("flags")),
+            ttl: try!(number("ttl")),
+        }))
+    }
+
+    fn read_binary(&mut self) -> Result<Option<ExportRecord>> {
+        if try!(self.reader.fill_buf()).is_empty() {
+            return Ok(None);
+        }
+        // A record cut short is reported like a malformed JSON one.
+        match read_binary_record(&mut self.reader) {
+            Ok(record) => Ok(Some(record)),
+            Err(e) => Err(ErrorKind::BadExport(self.record, format!("{}", e)).into()),
+        }
+    }
+}
+
+
+fn read_binary_record<R: BufRead>(reader: &mut R) -> ::std::io::Result<ExportRecord> {
+    let timestamp = try!(reader.read_u32::<LittleEndian>());
+    let flags = try!(reader.read_u32::<LittleEndian>());
+    let ttl = try!(reader.read_u32::<LittleEndian>());
+    let key_size = try!(read_varint(reader));
+    let value_size = try!(read_varint(reader));
+    Ok(ExportRecord {
+        key: try!(read_bytes(reader, key_size)),
+        value: try!(read_bytes(reader, value_size)),
+        timestamp: timestamp,
+        flags: flags,
+        ttl: ttl,
+    })
+}
+
+
+impl<R: BufRead> Iterator for ExportReader<R> {
+    type Item = Result<ExportRecord>;
+
+    fn next(&mut self) -> Option<Result<ExportRecord>> {
+        self.record += 1;
+        let record = match self.format {
+            ExportFormat::Json => self.read_json(),
+            ExportFormat::Binary => self.read_binary(),
+        };
+        match record {
+            Ok(Some(record)) => Some(Ok(record)),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+
+impl FromStr for ExportFormat {
+    type Err = ::error::Error;
+
+    fn from_str(s: &str) -> Result<ExportFormat> {
+        match s {
+            "json" => Ok(ExportFormat::Json),
+            "binary" => Ok(ExportFormat::Binary),
+            _ => Err(format!("unknown export format {}", s).into()),
+        }
+    }
+}
+
+
+#[test]
+fn test_round_trip() {
+    let records = vec![
+        ExportRecord { key: b"key".to_vec(), value: b"value".to_vec(), timestamp: 1, flags: 0, ttl: 0 },
+        ExportRecord { key: vec![0xff, b'\n'], value: Vec::new(), timestamp: 2, flags: 3, ttl: 4 },
+    ];
+    for &format in &[ExportFormat::Json, ExportFormat::Binary] {
+        let mut buf = Vec::new();
+        write_header(&mut buf, format).unwrap();
+        for record in records.iter() {
+            write_record(&mut buf, format, record).unwrap();
+        }
+        let read = ExportReader::new(buf.as_slice(), format).unwrap().collect::<Result<Vec<ExportRecord>>>().unwrap();
+        assert_eq!(read, records);
+    }
+
+    let mut buf = Vec::new();
+    write_header(&mut buf, ExportFormat::Json).unwrap();
+    write_record(&mut buf, ExportFormat::Json, &records[0]).unwrap();
+    assert!(String::from_utf8(buf.clone()).unwrap()
+        .ends_with("{\"key\":\"a2V5\",\"value\":\"dmFsdWU=\",\"ts\":1,\"flags\":0,\"ttl\":0}\n"));
+    assert!(ExportReader::new(buf.as_slice(), ExportFormat::Binary).is_err());
+
+    let json = "{\"bitcask_export\":1}\n\n{\"key\":\"a2V5\",\"value\":\"!\"}\n";
+    let mut reader = ExportReader::new(json.as_bytes(), ExportFormat::Json).unwrap();
+    match reader.next() {
+        Some(Err(e)) => match *e.kind() {
+            ErrorKind::BadExport(1, _) => (),
+            _ => panic!("unexpected error {:?}", e),
+        },
+        r => panic!("bad value accepted: {:?}", r),
+    }
+
+    let mut buf = Vec::new();
+    write_header(&mut buf, ExportFormat::Binary).unwrap();
+    for record in records.iter() {
+        write_record(&mut buf, ExportFormat::Binary, record).unwrap();
+    }
+    buf.pop();
+    let mut reader = ExportReader::new(buf.as_slice(), ExportFormat::Binary).unwrap();
+    assert_eq!(records[0], reader.next().unwrap().unwrap());
+    match reader.next() {
+        Some(Err(e)) => match *e.kind() {
+            ErrorKind::BadExport(2, _) => (),
+            _ => panic!("unexpected error {:?}", e),
+        },
+        r => panic!("truncated record accepted: {:?}", r),
+    }
+}
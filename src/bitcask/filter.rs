@@ -0,0 +1,32 @@
+/// What a merge knows about a record besides its key and value.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordMetadata {
+    /// When the record was written.
+    pub timestamp: u32,
+    /// The data file it is copied from.
+    pub file_id: u32,
+}
+
+
+/// What becomes of a record, see `CompactionFilter`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FilterDecision {
+    /// Copy it as it is.
+    Keep,
+    /// Delete the key.
+    Remove,
+    /// Copy it with this value instead, keeping its timestamp.
+    Replace(Vec<u8>),
+}
+
+
+/// Application logic run over each live record a merge copies, e.g. to
+/// purge keys of a retired prefix or migrate values to a new encoding. The
+/// value is given decrypted and decompressed. Records of the active file are
+/// only seen once it is sealed and merged, and a key written while the merge
+/// runs keeps its new value whatever the filter said about the old one.
+///
+/// Set it as `BitcaskOptions::compaction_filter`.
+pub trait CompactionFilter: Send + Sync {
+    fn filter(&self, key: &[u8], value: &[u8], metadata: &RecordMetadata) -> FilterDecision;
+}
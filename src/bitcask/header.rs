@@ -0,0 +1,131 @@
+use std::fs::File;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::Path;
+use byteorder::ReadBytesExt;
+use byteorder::WriteBytesExt;
+use byteorder::LittleEndian;
+use time;
+
+use ::error::ErrorKind;
+use ::error::Result;
+
+
+pub const DATA_MAGIC: [u8; 4] = *b"BCDF";
+pub const HINT_MAGIC: [u8; 4] = *b"BCHF";
+/// 1 added the file header, 2 varint key and value sizes, 3 CRC32 checksums
+/// on data and hint records, 4 a flags byte on data and hint records.
+pub const FORMAT_VERSION: u16 = 4;
+/// magic, version, reserved, file id and creation time.
+pub const HEADER_SIZE: u64 = 16;
+
+
+/// The header every data and hint file starts with. Files of older format
+/// versions, including headerless ones, are converted with `bitcask-upgrade`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileHeader {
+    pub magic: [u8; 4],
+    pub version: u16,
+    pub file_id: u32,
+    pub created: u32,
+}
+
+
+impl FileHeader {
+    pub fn new(magic: [u8; 4], file_id: u32) -> FileHeader {
+        FileHeader {
+            magic: magic,
+            version: FORMAT_VERSION,
+            file_id: file_id,
+            created: time::get_time().sec as u32,
+        }
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let mut buf = Vec::with_capacity(HEADER_SIZE as usize);
+        buf.extend_from_slice(&self.magic);
+        try!(buf.write_u16::<LittleEndian>(self.version));
+        try!(buf.write_u16::<LittleEndian>(0));
+        try!(buf.write_u32::<LittleEndian>(self.file_id));
+        try!(buf.write_u32::<LittleEndian>(self.created));
+        try!(writer.write_all(&buf));
+        Ok(())
+    }
+
+    /// Reads and validates the header of `file_path`, leaving the reader
+    /// positioned at the first record.
+    pub fn read_from<R: Read>(reader: &mut R, file_path: &Path, magic: [u8; 4]) -> Result<FileHeader> {
+        let name = file_path.to_string_lossy().into_owned();
+        let mut file_magic = [0; 4];
+        if reader.read_exact(&mut file_magic).is_err() || file_magic != magic {
+            return Err(ErrorKind::BadMagic(name).into());
+        }
+        let version = try!(reader.read_u16::<LittleEndian>());
+        if version != FORMAT_VERSION {
+            return Err(ErrorKind::UnsupportedVersion(name, version).into());
+        }
+        let _reserved = try!(reader.read_u16::<LittleEndian>());
+        let file_id = try!(reader.read_u32::<LittleEndian>());
+        let created = try!(reader.read_u32::<LittleEndian>());
+
+        Ok(FileHeader {
+            magic: magic,
+            version: version,
+            file_id: file_id,
+            created: created,
+        })
+    }
+
+    /// Checks the header of a file being opened. An empty file opened for
+    /// writing gets a fresh header instead.
+    pub fn init(file: &mut File, file_path: &Path, magic: [u8; 4], file_id: u32, writable: bool) -> Result<FileHeader> {
+        if writable && try!(file.metadata()).len() == 0 {
+            let header = FileHeader::new(magic, file_id);
+            try!(header.write_to(file));
+            return Ok(header);
+        }
+
+        try!(file.seek(SeekFrom::Start(0)));
+        let header = try!(FileHeader::read_from(file, file_path, magic));
+        if header.file_id != file_id {
+            return Err(format!("{} has file id {} in its header", file_path.display(), header.file_id).into());
+        }
+        Ok(header)
+    }
+}
+
+
+/// Format version of the file at `file_path`. Files without a header, or
+/// too short to hold one, are version 0.
+pub fn file_version(file_path: &Path, magic: [u8; 4]) -> Result<u16> {
+    let mut file = try!(File::open(file_path));
+    let mut file_magic = [0; 4];
+    if file.read_exact(&mut file_magic).is_err() || file_magic != magic {
+        return Ok(0);
+    }
+    Ok(try!(file.read_u16::<LittleEndian>()))
+}
+
+
+#[test]
+fn test_header_round_trip() {
+    let header = FileHeader::new(DATA_MAGIC, 7);
+    let mut buf = Vec::new();
+    header.write_to(&mut buf).unwrap();
+    assert_eq!(buf.len() as u64, HEADER_SIZE);
+
+    let read = FileHeader::read_from(&mut &buf[..], Path::new("7.data"), DATA_MAGIC).unwrap();
+    assert_eq!(header, read);
+    assert!(FileHeader::read_from(&mut &buf[..], Path::new("7.hint"), HINT_MAGIC).is_err());
+
+    buf[4] = 9;
+    match FileHeader::read_from(&mut &buf[..], Path::new("7.data"), DATA_MAGIC) {
+        Err(e) => match *e.kind() {
+            ErrorKind::UnsupportedVersion(_, 9) => (),
+            _ => panic!("unexpected error {:?}", e),
+        },
+        Ok(_) => panic!("version 9 accepted"),
+    }
+}
@@ -8,11 +8,23 @@ use byteorder::ReadBytesExt;
 use byteorder::WriteBytesExt;
 use byteorder::LittleEndian;
 
+use bitcask::header::FileHeader;
+use bitcask::crc::CrcReader;
+use bitcask::crc::crc32;
+use bitcask::data_file::read_bytes;
+use bitcask::data_file::record_len;
+use bitcask::header::HINT_MAGIC;
+use bitcask::varint::read_varint;
+use bitcask::varint::varint_len;
+use bitcask::varint::write_varint;
+use ::error::Result;
+
 
 #[derive(Debug)]
 pub struct HintFile {
     file: File,
     pub file_id: u32,
+    pub header: FileHeader,
     write_offset: Option<u64>,
 }
 
@@ -20,108 +32,174 @@ pub struct HintFile {
 #[derive(Debug)]
 pub struct HintEntry {
     pub timestamp: u32,
-    pub key_size: u8,
-    pub value_size: u32,
+    /// The flags of the data record.
+    pub flags: u8,
+    pub key_size: u32,
+    pub value_size: u64,
     pub value_pos: u64,
     pub key: Vec<u8>
 }
 
 
 impl HintFile {
-    pub fn new<P: AsRef<Path>>(path: P, file_id: u32, write_offset: Option<u64>) -> HintFile {
+    pub fn new<P: AsRef<Path>>(path: P, file_id: u32, write_offset: Option<u64>) -> Result<HintFile> {
         let file_path = path.as_ref().join(format!("{}.hint", file_id));
         let mut open_options = OpenOptions::new();
         open_options.read(true);
-        let mut file = match write_offset {
+        let mut file = try!(match write_offset {
             None => open_options.open(&file_path),
             Some(_) => {
                 open_options.create(true).append(true).open(&file_path)
             }
-        }.expect(&format!("open data file {}/{}", path.as_ref().to_string_lossy(), file_id));
+        });
+        let header = try!(FileHeader::init(&mut file, &file_path, HINT_MAGIC, file_id, write_offset.is_some()));
 
         let new_offset = if write_offset.is_some() {
-            Some(file.seek(std::io::SeekFrom::Current(0)).expect("seek file end"))
+            Some(try!(file.seek(std::io::SeekFrom::End(0))))
         } else {
             write_offset
         };
 
-        HintFile {
+        Ok(HintFile {
             file: file,
             file_id: file_id,
+            header: header,
             write_offset: new_offset,
-        }
+        })
     }
 
     fn is_readonly(&self) -> bool {
         return self.write_offset.is_none()
     }
 
-    pub fn write(&mut self, hint_entry: &HintEntry) -> Result<(), String> {
+    /// Flushes what was written to disk.
+    pub fn sync(&self) -> Result<()> {
+        try!(self.file.sync_all());
+        Ok(())
+    }
+
+    /// Reads the next entry. Unlike the iterator, a partial entry at the end,
+    /// e.g. one a writer is still appending, is left to be read again later.
+    pub fn read_entry(&mut self) -> Result<Option<HintEntry>> {
+        let pos = try!(self.file.seek(std::io::SeekFrom::Current(0)));
+        match HintEntry::decode(&mut self.file) {
+            Ok(hint_entry) => Ok(Some(hint_entry)),
+            Err(_) => {
+                try!(self.file.seek(std::io::SeekFrom::Start(pos)));
+                Ok(None)
+            }
+        }
+    }
+
+    /// Reads the next entry of a file no one writes to any more. Only the
+    /// end of the file ends it; a partial or corrupt entry is an error.
+    pub fn read_entry_strict(&mut self) -> Result<Option<HintEntry>> {
+        let pos = try!(self.file.seek(std::io::SeekFrom::Current(0)));
+        if pos >= try!(self.file.metadata()).len() {
+            return Ok(None);
+        }
+        match HintEntry::decode(&mut self.file) {
+            Ok(hint_entry) => Ok(Some(hint_entry)),
+            Err(e) => Err(format!("{}.hint is corrupt at offset {}: {}", self.file_id, pos, e).into()),
+        }
+    }
+
+    pub fn write(&mut self, hint_entry: &HintEntry) -> Result<()> {
+        self.write_entries(std::slice::from_ref(hint_entry))
+    }
+
+    /// Appends all entries with a single write.
+    pub fn write_entries(&mut self, hint_entries: &[HintEntry]) -> Result<()> {
         if self.is_readonly() {
-            unimplemented!()
+            return Err(format!("hint file {} is read only", self.file_id).into());
         }
 
-        if let Err(_) = self.file.seek(std::io::SeekFrom::End(0)) {
-            panic!("seek end")
+        let mut buf = Vec::new();
+        for hint_entry in hint_entries {
+            try!(hint_entry.encode(&mut buf));
         }
-        self.file.write_u32::<LittleEndian>(hint_entry.timestamp).expect("write timestamp");
-        self.file.write(&[hint_entry.key_size]).expect("write key size");
-        self.file.write_u32::<LittleEndian>(hint_entry.value_size).expect("write value size");
-        self.file.write_u64::<LittleEndian>(hint_entry.value_pos).expect("write value pos");
-        self.file.write_all(&hint_entry.key).expect("write key");
-        self.file.flush().expect("flush");
+        try!(self.file.seek(std::io::SeekFrom::End(0)));
+        try!(self.file.write_all(&buf));
+        try!(self.file.flush());
 
         Ok(())
     }
 }
 
 
-impl Iterator for HintFile {
-    type Item = HintEntry;
-    fn next(&mut self) -> Option<HintEntry> {
-        let timestamp = match self.file.read_u32::<LittleEndian>() {
-            Ok(t) => t,
-            Err(_) => return None
-        };
-        let mut key_buf: [u8; 1] = [0];
-        let key_size = match self.file.read(&mut key_buf) {
-            Ok(_) => key_buf[0],
-            Err(_) => return None
-        };
-        let value_size = match self.file.read_u32::<LittleEndian>() {
-            Ok(v) => v,
-            Err(_) => return None
-        };
-        let value_pos = match self.file.read_u64::<LittleEndian>() {
-            Ok(p) => p,
-            Err(_) => return None
-        };
-        let mut key = vec![0; key_size as usize];
-        if let Err(_) = self.file.read_exact(&mut key) {
-            return None;
+impl HintEntry {
+    /// Appends the on-disk representation of the entry to `buf`, preceded
+    /// by its checksum.
+    pub fn encode(&self, buf: &mut Vec<u8>) -> Result<()> {
+        let start = buf.len();
+        try!(buf.write_u32::<LittleEndian>(0));
+        try!(buf.write_u32::<LittleEndian>(self.timestamp));
+        buf.push(self.flags);
+        write_varint(buf, self.key_size as u64);
+        write_varint(buf, self.value_size);
+        try!(buf.write_u64::<LittleEndian>(self.value_pos));
+        buf.extend_from_slice(&self.key);
+        let crc = crc32(&buf[start + 4..]);
+        try!((&mut buf[start..start + 4]).write_u32::<LittleEndian>(crc));
+        Ok(())
+    }
+
+    /// Reads an entry, failing with `InvalidData` if its checksum doesn't
+    /// match.
+    pub fn decode<R: Read>(reader: &mut R) -> std::io::Result<HintEntry> {
+        let crc = try!(reader.read_u32::<LittleEndian>());
+        let mut reader = CrcReader::new(reader);
+        let timestamp = try!(reader.read_u32::<LittleEndian>());
+        let flags = try!(reader.read_u8());
+        let key_size = try!(read_varint(&mut reader));
+        let value_size = try!(read_varint(&mut reader));
+        let value_pos = try!(reader.read_u64::<LittleEndian>());
+        let key = try!(read_bytes(&mut reader, key_size));
+        if reader.crc.finish() != crc {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "checksum mismatch"));
         }
 
-        Some(HintEntry {
+        Ok(HintEntry {
             timestamp: timestamp,
-            key_size: key_size,
+            flags: flags,
+            key_size: key_size as u32,
             value_size: value_size,
             value_pos: value_pos,
             key: key
         })
     }
+
+    pub fn encoded_len(&self) -> u64 {
+        (4 + 4 + 1 + varint_len(self.key_size as u64) + varint_len(self.value_size) + 8) as u64 + self.key_size as u64
+    }
+
+    /// Length of the data record the entry points to.
+    pub fn data_len(&self) -> u64 {
+        record_len(self.key_size as u64, self.value_size)
+    }
+}
+
+
+impl Iterator for HintFile {
+    type Item = HintEntry;
+    fn next(&mut self) -> Option<HintEntry> {
+        HintEntry::decode(&mut self.file).ok()
+    }
 }
 
 
 #[test]
 fn test_read_write() {
+    let dir = ::bitcask::bitcask::test_dir("hint-file");
     {
-        let mut db = HintFile::new(".".to_owned(), 0, Some(0));
+        let mut db = HintFile::new(dir.path(), 0, Some(0)).unwrap();
         let value = "你好".as_bytes().to_vec();
         let key = "哈哈".as_bytes().to_vec();
         let entry = HintEntry {
             timestamp: 1,
-            key_size: key.len() as u8,
-            value_size: value.len() as u32,
+            flags: 0,
+            key_size: key.len() as u32,
+            value_size: value.len() as u64,
             value_pos: db.write_offset.unwrap(),
             key: key
         };
@@ -130,7 +208,7 @@ fn test_read_write() {
         assert!(db.write(&entry).is_ok());
     }
     {
-        let db = HintFile::new(".".to_owned(), 0, None);
+        let db = HintFile::new(dir.path(), 0, None).unwrap();
         for entry in db {
             println!("read {:?}", entry);
         }
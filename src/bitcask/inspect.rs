@@ -0,0 +1,198 @@
+use std::ascii;
+use std::cmp;
+use std::fs::File;
+use std::io::BufReader;
+use std::io::Write;
+use std::path::Path;
+
+use bitcask::data_file::DataEntry;
+use bitcask::header::FileHeader;
+use bitcask::header::DATA_MAGIC;
+use bitcask::header::HEADER_SIZE;
+use bitcask::header::HINT_MAGIC;
+use bitcask::hint_file::HintEntry;
+use bitcask::lock::DirLock;
+use bitcask::manifest::Manifest;
+use bitcask::manifest::list_files;
+use bitcask::recovery::TailRecovery;
+use bitcask::recovery::recover_tail;
+use bitcask::recovery::scan_data_file;
+use bitcask::recovery::scan_hint_file;
+use ::error::Result;
+
+
+/// Values longer than this are cut short by `dump`.
+const DUMP_VALUE_SIZE: usize = 32;
+
+
+/// Writes the header and every record of a data or hint file to `out`, one
+/// line each, up to the first one that doesn't decode.
+pub fn dump<W: Write>(file_path: &Path, out: &mut W) -> Result<()> {
+    let magic = match file_path.extension().and_then(|e| e.to_str()) {
+        Some("data") => DATA_MAGIC,
+        Some("hint") => HINT_MAGIC,
+        _ => return Err(format!("{} is neither a data nor a hint file", file_path.display()).into()),
+    };
+    let file = try!(File::open(file_path));
+    let len = try!(file.metadata()).len();
+    let mut reader = BufReader::new(file);
+    let header = try!(FileHeader::read_from(&mut reader, file_path, magic));
+    try!(writeln!(out, "{}: version {} file_id {} created {}",
+                  file_path.display(), header.version, header.file_id, header.created));
+
+    let mut offset = HEADER_SIZE;
+    if magic == DATA_MAGIC {
+        while let Ok(entry) = DataEntry::decode(&mut reader) {
+            let shown = cmp::min(entry.value.len(), DUMP_VALUE_SIZE);
+            try!(writeln!(out, "@{} ts={} flags={:#04x} key={} value_size={} value={}{}",
+                          offset, entry.timestamp, entry.flags, escape(&entry.key), entry.value_size,
+                          escape(&entry.value[..shown]), if shown < entry.value.len() { "..." } else { "" }));
+            offset += entry.encoded_len();
+        }
+    } else {
+        while let Ok(hint_entry) = HintEntry::decode(&mut reader) {
+            try!(writeln!(out, "@{} ts={} flags={:#04x} key={} value_size={} value_pos={}",
+                          offset, hint_entry.timestamp, hint_entry.flags, escape(&hint_entry.key),
+                          hint_entry.value_size, hint_entry.value_pos));
+            offset += hint_entry.encoded_len();
+        }
+    }
+    if offset < len {
+        try!(writeln!(out, "{} bytes of torn or corrupt records at offset {}", len - offset, offset));
+    }
+    Ok(())
+}
+
+
+/// Checks the files of the store in `path` without changing them: every
+/// record's checksum, and that each hint file has a matching entry for every
+/// record of its data file. Returns what is wrong, nothing if all is well.
+///
+/// The active files of a running store may have a record half written, so
+/// they can show a torn tail that isn't one.
+pub fn verify<P: AsRef<Path>>(path: P) -> Result<Vec<String>> {
+    let path = path.as_ref();
+    let mut problems = Vec::new();
+    let manifest = match try!(Manifest::load(path)) {
+        Some(manifest) => {
+            for (file_id, file_name) in list_files(path) {
+                if file_id != manifest.active && !manifest.sealed.contains(&file_id)
+                    && !manifest.merging.contains(&file_id) {
+                    problems.push(format!("{} is not listed in the manifest", file_name));
+                }
+            }
+            manifest
+        },
+        None => try!(Manifest::scan(path)),
+    };
+
+    for file_id in manifest.live_files() {
+        let data_path = path.join(format!("{}.data", file_id));
+        let hint_path = path.join(format!("{}.hint", file_id));
+        if !data_path.exists() {
+            problems.push(format!("{}.data is missing", file_id));
+            continue;
+        }
+        let records = match scan_data_file(&data_path, false) {
+            Ok((valid, len, records)) => {
+                if valid < len {
+                    problems.push(format!("{}.data: {} bytes of torn or corrupt records at offset {}",
+                                          file_id, len - valid, valid));
+                }
+                records
+            },
+            Err(e) => {
+                problems.push(format!("{}.data: {}", file_id, e));
+                continue;
+            },
+        };
+        if !hint_path.exists() {
+            problems.push(format!("{}.hint is missing", file_id));
+            continue;
+        }
+        match scan_hint_file(&hint_path, &records) {
+            Ok((valid, len, count)) => {
+                if valid < len {
+                    problems.push(format!("{}.hint: {} bytes at offset {} don't match {}.data",
+                                          file_id, len - valid, valid, file_id));
+                }
+                if count < records.len() {
+                    problems.push(format!("{}.hint: has entries for {} of {} records", file_id, count, records.len()));
+                }
+            },
+            Err(e) => problems.push(format!("{}.hint: {}", file_id, e)),
+        }
+    }
+    Ok(problems)
+}
+
+
+/// Does for every file of the store in `path` what opening it does for the
+/// active one: cuts data files back to their last valid record and rewrites
+/// the hint entries from the first one that doesn't match. Unlike opening,
+/// it also cuts at a corrupt record in the middle of a file, dropping the
+/// records after it. Takes the
+/// directory lock, so the store must not be open. Returns what was changed,
+/// by file id.
+pub fn repair<P: AsRef<Path>>(path: P) -> Result<Vec<(u32, TailRecovery)>> {
+    let _lock = try!(DirLock::acquire(&path));
+    let manifest = match try!(Manifest::load(&path)) {
+        Some(manifest) => manifest,
+        None => try!(Manifest::scan(&path)),
+    };
+    let mut repaired = Vec::new();
+    for file_id in manifest.live_files() {
+        let recovery = try!(recover_tail(&path, file_id, true, true));
+        if recovery != TailRecovery::default() {
+            repaired.push((file_id, recovery));
+        }
+    }
+    Ok(repaired)
+}
+
+
+/// Shows binary keys and values as text, with `\x..` escapes.
+pub fn escape(bytes: &[u8]) -> String {
+    bytes.iter().flat_map(|&b| ascii::escape_default(b)).map(|b| b as char).collect()
+}
+
+
+#[test]
+fn test_verify_and_repair() {
+    use std::fs;
+    use std::fs::OpenOptions;
+    use bitcask::bitcask::Bitcask;
+    use bitcask::bitcask::BitcaskOptions;
+
+    let dir = ::bitcask::bitcask::test_dir("inspect");
+    let path = dir.0.clone();
+    {
+        let mut bitcask = Bitcask::new(dir.path(), BitcaskOptions::default()).unwrap();
+        bitcask.put("a", b"1".to_vec()).unwrap();
+        bitcask.put(vec![b'b', 0xff], b"2".to_vec()).unwrap();
+    }
+    assert!(verify(&path).unwrap().is_empty());
+
+    let mut out = Vec::new();
+    dump(&path.join("0.data"), &mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert_eq!(3, out.lines().count());
+    assert!(out.contains("@16 ts="));
+    assert!(out.contains("key=b\\xff value_size=1 value=2"));
+
+    OpenOptions::new().append(true).open(path.join("0.data")).unwrap().write_all(b"torn").unwrap();
+    fs::remove_file(path.join("0.hint")).unwrap();
+    File::create(path.join("7.data")).unwrap();
+    let problems = verify(&path).unwrap();
+    assert_eq!(problems, vec![
+        "7.data is not listed in the manifest".to_owned(),
+        "0.data: 4 bytes of torn or corrupt records at offset 43".to_owned(),
+        "0.hint is missing".to_owned(),
+    ]);
+
+    fs::remove_file(path.join("7.data")).unwrap();
+    let repaired = repair(&path).unwrap();
+    assert_eq!(repaired, vec![(0, TailRecovery { data_discarded: 4, hint_discarded: 0, hints_added: 2 })]);
+    assert!(verify(&path).unwrap().is_empty());
+    assert!(repair(&path).unwrap().is_empty());
+}
@@ -0,0 +1,155 @@
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::ops::Bound;
+
+
+/// Where the latest value of a key is stored.
+#[derive(Clone)]
+pub struct Entry {
+    pub timestamp: u32,
+    pub flags: u8,
+    pub value_size: u64,
+    pub value_pos: u64,
+    pub file_id: u32,
+}
+
+
+/// The in-memory index from every live key to its `Entry`. A hash map is
+/// the cheapest; the ordered map makes range and prefix scans proportional
+/// to what they return instead of to the whole keydir.
+#[derive(Clone)]
+pub enum KeyDir {
+    Hashed(HashMap<Vec<u8>, Entry>),
+    Ordered(BTreeMap<Vec<u8>, Entry>),
+}
+
+
+impl KeyDir {
+    pub fn new(ordered: bool) -> KeyDir {
+        if ordered {
+            KeyDir::Ordered(BTreeMap::new())
+        } else {
+            KeyDir::Hashed(HashMap::new())
+        }
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&Entry> {
+        match *self {
+            KeyDir::Hashed(ref map) => map.get(key),
+            KeyDir::Ordered(ref map) => map.get(key),
+        }
+    }
+
+    pub fn insert(&mut self, key: Vec<u8>, entry: Entry) -> Option<Entry> {
+        match *self {
+            KeyDir::Hashed(ref mut map) => map.insert(key, entry),
+            KeyDir::Ordered(ref mut map) => map.insert(key, entry),
+        }
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Option<Entry> {
+        match *self {
+            KeyDir::Hashed(ref mut map) => map.remove(key),
+            KeyDir::Ordered(ref mut map) => map.remove(key),
+        }
+    }
+
+    /// Keeps only the entries for which `f` returns true.
+    pub fn retain<F: FnMut(&Entry) -> bool>(&mut self, mut f: F) {
+        match *self {
+            KeyDir::Hashed(ref mut map) => map.retain(|_, entry| f(entry)),
+            KeyDir::Ordered(ref mut map) => map.retain(|_, entry| f(entry)),
+        }
+    }
+
+    /// Every key, in order if the keydir is ordered.
+    pub fn keys(&self) -> Vec<Vec<u8>> {
+        match *self {
+            KeyDir::Hashed(ref map) => map.keys().cloned().collect(),
+            KeyDir::Ordered(ref map) => map.keys().cloned().collect(),
+        }
+    }
+
+    /// The keys between `start` and `end`, in order. A hashed keydir has to
+    /// look at, and then sort, every key.
+    pub fn keys_between(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Vec<Vec<u8>> {
+        let after_start = |key: &[u8]| match start {
+            Bound::Included(s) => key >= s,
+            Bound::Excluded(s) => key > s,
+            Bound::Unbounded => true,
+        };
+        let before_end = |key: &[u8]| match end {
+            Bound::Included(e) => key <= e,
+            Bound::Excluded(e) => key < e,
+            Bound::Unbounded => true,
+        };
+        match *self {
+            KeyDir::Hashed(ref map) => {
+                let mut keys = map.keys()
+                    .filter(|key| after_start(key) && before_end(key))
+                    .cloned()
+                    .collect::<Vec<Vec<u8>>>();
+                keys.sort();
+                keys
+            },
+            KeyDir::Ordered(ref map) => {
+                // `BTreeMap::range` panics on a start after the end.
+                let empty = match (start, end) {
+                    (Bound::Included(s), Bound::Included(e)) => s > e,
+                    (Bound::Included(s), Bound::Excluded(e)) |
+                    (Bound::Excluded(s), Bound::Included(e)) |
+                    (Bound::Excluded(s), Bound::Excluded(e)) => s >= e,
+                    _ => false,
+                };
+                if empty {
+                    return Vec::new();
+                }
+                map.range::<[u8], _>((start, end)).map(|(key, _)| key.clone()).collect()
+            },
+        }
+    }
+}
+
+
+/// The range of keys starting with `prefix`, for `KeyDir::keys_between`.
+pub fn prefix_range(prefix: &[u8]) -> (Bound<&[u8]>, Bound<Vec<u8>>) {
+    // The first key after all those with the prefix: drop trailing 0xff
+    // bytes, which can't be incremented, and increment the last one left.
+    let mut end = prefix.to_vec();
+    while end.last() == Some(&0xff) {
+        end.pop();
+    }
+    let end = match end.pop() {
+        Some(last) => {
+            end.push(last + 1);
+            Bound::Excluded(end)
+        },
+        None => Bound::Unbounded,
+    };
+    (Bound::Included(prefix), end)
+}
+
+
+#[test]
+fn test_keys_between() {
+    for &ordered in &[false, true] {
+        let mut keydir = KeyDir::new(ordered);
+        for key in &["b", "a", "ab", "c", "user:1", "user:2", "user;"] {
+            keydir.insert(key.as_bytes().to_vec(), Entry { timestamp: 0, flags: 0, value_size: 0, value_pos: 0, file_id: 0 });
+        }
+        let keys = |keys: Vec<Vec<u8>>| keys.into_iter().map(|k| String::from_utf8(k).unwrap()).collect::<Vec<String>>();
+
+        assert_eq!(keys(keydir.keys_between(Bound::Included(b"a"), Bound::Excluded(b"c"))), vec!["a", "ab", "b"]);
+        assert_eq!(keys(keydir.keys_between(Bound::Excluded(b"b"), Bound::Unbounded)), vec!["c", "user:1", "user:2", "user;"]);
+        assert!(keydir.keys_between(Bound::Included(b"c"), Bound::Excluded(b"a")).is_empty());
+
+        let (start, end) = prefix_range(b"user:");
+        let end = match end {
+            Bound::Excluded(ref e) => Bound::Excluded(e.as_slice()),
+            _ => panic!("unbounded prefix"),
+        };
+        assert_eq!(keys(keydir.keys_between(start, end)), vec!["user:1", "user:2"]);
+    }
+    assert_eq!(prefix_range(&[0x61, 0xff]).1, Bound::Excluded(vec![0x62]));
+    assert_eq!(prefix_range(&[0xff]).1, Bound::Unbounded);
+}
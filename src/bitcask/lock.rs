@@ -0,0 +1,71 @@
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::process;
+use nix;
+use nix::errno::Errno;
+use nix::fcntl::FlockArg;
+use nix::fcntl::flock;
+
+use ::error::ChainErr;
+use ::error::ErrorKind;
+use ::error::Result;
+
+
+/// An exclusive `flock` on the `LOCK` file of a directory, held for as long
+/// as the value lives. It keeps a second writer from appending to the same
+/// files.
+#[derive(Debug)]
+pub struct DirLock {
+    file: File,
+}
+
+
+impl DirLock {
+    pub fn acquire<P: AsRef<Path>>(path: P) -> Result<DirLock> {
+        let lock_path = path.as_ref().join("LOCK");
+        let mut file = try!(OpenOptions::new().write(true).create(true).open(&lock_path));
+        match flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+            Ok(()) => (),
+            Err(nix::Error::Sys(Errno::EAGAIN)) => {
+                return Err(ErrorKind::Locked(path.as_ref().to_string_lossy().into_owned()).into());
+            },
+            Err(e) => return Err(e).chain_err(|| format!("lock {}", lock_path.display())),
+        }
+
+        // Only informational, for whoever finds the directory locked.
+        try!(file.set_len(0));
+        try!(write!(file, "{}\n", process::id()));
+
+        Ok(DirLock {
+            file: file,
+        })
+    }
+}
+
+
+impl Drop for DirLock {
+    fn drop(&mut self) {
+        let _ = flock(self.file.as_raw_fd(), FlockArg::Unlock);
+    }
+}
+
+
+#[test]
+fn test_dir_lock() {
+    let dir = ::bitcask::bitcask::test_dir("lock");
+    let path = dir.0.clone();
+
+    let lock = DirLock::acquire(&path).unwrap();
+    match DirLock::acquire(&path) {
+        Err(e) => match *e.kind() {
+            ErrorKind::Locked(_) => (),
+            _ => panic!("unexpected error {:?}", e),
+        },
+        Ok(_) => panic!("locked twice"),
+    }
+    drop(lock);
+    DirLock::acquire(&path).unwrap();
+}
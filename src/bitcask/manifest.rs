@@ -0,0 +1,243 @@
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+
+use ::error::ErrorKind;
+use ::error::Result;
+
+
+pub const MANIFEST_FILE: &str = "MANIFEST";
+const MANIFEST_TMP_FILE: &str = "MANIFEST.tmp";
+const MANIFEST_VERSION: u32 = 1;
+
+
+/// The set of files that make up a store. Only files listed here are ever
+/// loaded; anything else with a data or hint extension is a leftover, e.g.
+/// of a crashed merge, and is removed when a writer opens the directory.
+///
+/// It is a small text file, replaced as a whole:
+///
+/// ```text
+/// bitcask-manifest 1
+/// active 7
+/// sealed 3 5 6
+/// merging 8
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Manifest {
+    /// The file pair being appended to.
+    pub active: u32,
+    /// File pairs that are complete and never change again, in the order
+    /// their records are applied on open, so that later ones win. That is
+    /// oldest first, except that a merge lists its output where the last
+    /// of its inputs was.
+    pub sealed: Vec<u32>,
+    /// File pairs a merge is still writing. They hold no data of their own
+    /// until the merge lists them as sealed.
+    pub merging: BTreeSet<u32>,
+}
+
+
+impl Manifest {
+    pub fn new(active: u32) -> Manifest {
+        Manifest {
+            active: active,
+            sealed: Vec::new(),
+            merging: BTreeSet::new(),
+        }
+    }
+
+    /// Reads the manifest of `path`, if it has one.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Option<Manifest>> {
+        let file_path = path.as_ref().join(MANIFEST_FILE);
+        let mut contents = String::new();
+        match File::open(&file_path) {
+            Ok(mut file) => try!(file.read_to_string(&mut contents)),
+            Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        match Manifest::parse(&contents) {
+            Some(manifest) => Ok(Some(manifest)),
+            None => Err(ErrorKind::BadManifest(file_path.to_string_lossy().into_owned()).into()),
+        }
+    }
+
+    /// Builds a manifest for a directory written before manifests existed:
+    /// the newest data or hint file is the active one, all others are sealed.
+    pub fn scan<P: AsRef<Path>>(path: P) -> Result<Manifest> {
+        let mut file_ids = list_files(path.as_ref()).into_iter().map(|(id, _)| id).collect::<BTreeSet<u32>>();
+        let active = file_ids.iter().next_back().cloned().unwrap_or(0);
+        file_ids.remove(&active);
+        let mut manifest = Manifest::new(active);
+        manifest.sealed = file_ids.into_iter().collect();
+        Ok(manifest)
+    }
+
+    /// Replaces the manifest of `path` with this one. It is written to a
+    /// temporary file which is renamed over the old one, so a crash leaves
+    /// either of them in place.
+    pub fn store<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let tmp_path = path.as_ref().join(MANIFEST_TMP_FILE);
+        {
+            let mut tmp = try!(OpenOptions::new().write(true).create(true).truncate(true).open(&tmp_path));
+            try!(tmp.write_all(self.to_string().as_bytes()));
+            try!(tmp.sync_all());
+        }
+        try!(fs::rename(&tmp_path, path.as_ref().join(MANIFEST_FILE)));
+        try!(try!(File::open(path.as_ref())).sync_all());
+        Ok(())
+    }
+
+    /// Every file id that holds live data, oldest first.
+    pub fn live_files(&self) -> Vec<u32> {
+        let mut file_ids = self.sealed.clone();
+        if !self.sealed.contains(&self.active) {
+            file_ids.push(self.active);
+        }
+        file_ids
+    }
+
+    /// The id after every file listed, for the next file to create.
+    pub fn next_file_id(&self) -> u32 {
+        let max = self.sealed.iter().chain(self.merging.iter()).cloned().max().unwrap_or(0);
+        ::std::cmp::max(max, self.active) + 1
+    }
+
+    /// Removes the data and hint files of `path` not listed as active or
+    /// sealed, and forgets about unfinished merges, which needs a `store`
+    /// afterwards. Returns the removed files.
+    pub fn collect_garbage<P: AsRef<Path>>(&mut self, path: P) -> Result<Vec<String>> {
+        let mut removed = Vec::new();
+        for (file_id, file_name) in list_files(path.as_ref()) {
+            if file_id == self.active || self.sealed.contains(&file_id) {
+                continue;
+            }
+            try!(fs::remove_file(path.as_ref().join(&file_name)));
+            println!("removed {}, not listed in the manifest", file_name);
+            removed.push(file_name);
+        }
+        let _ = fs::remove_file(path.as_ref().join(MANIFEST_TMP_FILE));
+        self.merging.clear();
+        Ok(removed)
+    }
+
+    fn parse(contents: &str) -> Option<Manifest> {
+        let mut lines = contents.lines();
+        if lines.next() != Some(&format!("bitcask-manifest {}", MANIFEST_VERSION)[..]) {
+            return None;
+        }
+        let mut active = None;
+        let mut sealed = Vec::new();
+        let mut merging = BTreeSet::new();
+        for line in lines {
+            let mut words = line.split_whitespace();
+            let name = match words.next() {
+                Some(name) => name,
+                None => continue,
+            };
+            let mut ids = Vec::new();
+            for word in words {
+                match word.parse::<u32>() {
+                    Ok(id) => ids.push(id),
+                    Err(_) => return None,
+                }
+            }
+            match name {
+                "active" if ids.len() == 1 => active = Some(ids[0]),
+                "sealed" => sealed = ids,
+                "merging" => merging = ids.into_iter().collect(),
+                _ => return None,
+            }
+        }
+        active.map(|active| Manifest {
+            active: active,
+            sealed: sealed,
+            merging: merging,
+        })
+    }
+}
+
+
+impl fmt::Display for Manifest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let join = |ids: &mut dyn Iterator<Item=&u32>| {
+            ids.map(|id| format!(" {}", id)).collect::<String>()
+        };
+        write!(f, "bitcask-manifest {}\nactive {}\nsealed{}\nmerging{}\n",
+               MANIFEST_VERSION, self.active, join(&mut self.sealed.iter()), join(&mut self.merging.iter()))
+    }
+}
+
+
+/// The data and hint files of `path`, as (file id, file name).
+pub fn list_files(path: &Path) -> Vec<(u32, String)> {
+    let mut files = Vec::new();
+    let entries = match path.read_dir() {
+        Ok(entries) => entries,
+        Err(_) => return files,
+    };
+    for entry in entries {
+        let file_path = match entry {
+            Ok(e) => e.path(),
+            Err(_) => continue
+        };
+        match file_path.extension().and_then(|e| e.to_str()) {
+            Some("data") | Some("hint") => (),
+            _ => continue
+        }
+        let file_id = match file_path.file_stem().and_then(|s| s.to_str()).map(|s| s.parse::<u32>()) {
+            Some(Ok(i)) => i,
+            _ => continue
+        };
+        if let Some(name) = file_path.file_name() {
+            files.push((file_id, name.to_string_lossy().into_owned()));
+        }
+    }
+    files
+}
+
+
+#[test]
+fn test_manifest() {
+    let dir = ::bitcask::bitcask::test_dir("manifest");
+    let path = dir.0.clone();
+    for name in &["1.data", "1.hint", "2.data", "2.hint", "3.data", "3.hint", "4.data", "junk"] {
+        File::create(path.join(name)).unwrap();
+    }
+    assert_eq!(None, Manifest::load(&path).unwrap());
+
+    let scanned = Manifest::scan(&path).unwrap();
+    assert_eq!(4, scanned.active);
+    assert_eq!(vec![1, 2, 3], scanned.sealed.iter().cloned().collect::<Vec<u32>>());
+
+    let mut manifest = Manifest::new(3);
+    manifest.sealed.push(1);
+    manifest.merging.insert(4);
+    manifest.store(&path).unwrap();
+    assert_eq!(Some(manifest.clone()), Manifest::load(&path).unwrap());
+    assert_eq!(vec![1, 3], manifest.live_files());
+    assert_eq!(5, manifest.next_file_id());
+
+    let mut removed = manifest.collect_garbage(&path).unwrap();
+    removed.sort();
+    assert_eq!(vec!["2.data", "2.hint", "4.data"], removed);
+    assert!(path.join("junk").exists());
+    assert!(manifest.merging.is_empty());
+
+    File::create(path.join(MANIFEST_FILE)).unwrap().write_all(b"bitcask-manifest 1\nactive x\n").unwrap();
+    assert!(Manifest::load(&path).is_err());
+
+    File::create(path.join(MANIFEST_FILE)).unwrap().write_all(b"bitcask-manifest 2\nactive 3\n").unwrap();
+    assert!(Manifest::load(&path).is_err());
+
+    // The order of sealed files is kept.
+    manifest.sealed = vec![5, 1, 3];
+    manifest.active = 6;
+    manifest.store(&path).unwrap();
+    assert_eq!(vec![5, 1, 3, 6], Manifest::load(&path).unwrap().unwrap().live_files());
+}
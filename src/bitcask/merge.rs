@@ -0,0 +1,417 @@
+use std::collections::HashSet;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::Mutex;
+use time;
+
+use bitcask::bitcask::BitcaskOptions;
+use bitcask::bitcask::encode_record;
+use bitcask::compression::Compression;
+use bitcask::data_file::DataFile;
+use bitcask::data_file::TOMBSTONE;
+use bitcask::encryption::ENCRYPTED;
+use bitcask::encryption::Keyring;
+use bitcask::filter::FilterDecision;
+use bitcask::filter::RecordMetadata;
+use bitcask::hint_file::HintEntry;
+use bitcask::hint_file::HintFile;
+use bitcask::keydir::Entry;
+use bitcask::keydir::KeyDir;
+use bitcask::snapshot::require_keyring;
+use bitcask::stats::FileStats;
+use bitcask::throttle::Throttle;
+use ::error::Result;
+
+
+/// Local time of day merges may start in, like `02:00-05:00`. It can wrap
+/// past midnight, as in `23:00-01:00`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MergeWindow {
+    /// Minutes after midnight.
+    start: u32,
+    end: u32,
+}
+
+
+/// How merges are getting on, from `Bitcask::merge_stats`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct MergeStats {
+    pub running: bool,
+    /// The files the running merge rewrites.
+    pub files: Vec<u32>,
+    /// Record bytes of those files, and how many of them it has read.
+    pub bytes_to_read: u64,
+    pub bytes_read: u64,
+    /// Bytes of records the running merge has written.
+    pub bytes_written: u64,
+    /// Merges completed since the store was opened, how many files they
+    /// replaced and how many bytes that freed.
+    pub merges: u64,
+    pub files_merged: u64,
+    pub bytes_reclaimed: u64,
+    /// Whether merges are paused, and their I/O limit in bytes per second.
+    pub paused: bool,
+    pub rate_limit: u64,
+}
+
+
+/// A sealed file as the merge policy sees it.
+pub struct Candidate {
+    pub stats: FileStats,
+    /// Creation time from the file header.
+    pub created: u32,
+}
+
+
+/// A record a merge copied, or removed as the compaction filter said, that
+/// the keydir pointed at when it started. It is only repointed or removed if
+/// it still does when the merge finishes.
+pub struct Move {
+    pub key: Vec<u8>,
+    pub file_id: u32,
+    pub value_pos: u64,
+    /// Where the copy is, `None` if the key is removed.
+    pub entry: Option<Entry>,
+    /// Length of the copy.
+    pub len: u64,
+}
+
+
+/// What a merge job shares with the store it merges.
+pub struct MergeContext {
+    pub option: BitcaskOptions,
+    pub path: String,
+    pub stats: Arc<Mutex<MergeStats>>,
+    pub throttle: Arc<Throttle>,
+}
+
+
+/// What a merge job did, for `Bitcask::finish_merge` to apply.
+pub struct MergeOutcome {
+    pub inputs: Vec<u32>,
+    pub outputs: Vec<u32>,
+    /// The records to repoint.
+    pub moves: Vec<Move>,
+    /// Tombstones copied, as (file id, record length).
+    pub tombstones: Vec<(u32, u64)>,
+}
+
+
+/// A merge started by `Bitcask::start_merge`. `run` does the copying and
+/// needs no access to the store but for new file ids, so that reads and
+/// writes carry on meanwhile; `Bitcask::finish_merge` then swaps the files.
+pub struct MergeJob {
+    inputs: Vec<Arc<DataFile>>,
+    /// Inputs whose tombstones are copied, because files listed before them
+    /// that aren't merged may hold records they delete.
+    keep_tombstones: HashSet<u32>,
+    entries: Arc<KeyDir>,
+    keyring: Option<Arc<Keyring>>,
+    context: MergeContext,
+    output: Option<(DataFile, HintFile)>,
+    outputs: Vec<u32>,
+    moves: Vec<Move>,
+    /// Tombstones copied, as (file id, record length).
+    tombstones: Vec<(u32, u64)>,
+}
+
+
+impl MergeWindow {
+    pub fn new(start_hour: u32, start_minute: u32, end_hour: u32, end_minute: u32) -> MergeWindow {
+        MergeWindow {
+            start: start_hour * 60 + start_minute,
+            end: end_hour * 60 + end_minute,
+        }
+    }
+
+    /// Whether `minute`, counted from midnight, is in the window.
+    pub fn contains(&self, minute: u32) -> bool {
+        if self.start <= self.end {
+            minute >= self.start && minute < self.end
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+
+    /// Whether the window is open now.
+    pub fn is_open(&self) -> bool {
+        let now = time::now();
+        self.contains(now.tm_hour as u32 * 60 + now.tm_min as u32)
+    }
+}
+
+
+impl FromStr for MergeWindow {
+    type Err = ::error::Error;
+
+    fn from_str(s: &str) -> Result<MergeWindow> {
+        let parse_time = |t: &str| -> Option<u32> {
+            let mut parts = t.splitn(2, ':');
+            let hour = parts.next().and_then(|h| h.parse::<u32>().ok());
+            let minute = parts.next().and_then(|m| m.parse::<u32>().ok());
+            match (hour, minute) {
+                (Some(h), Some(m)) if h <= 24 && m < 60 && h * 60 + m <= 24 * 60 => Some(h * 60 + m),
+                _ => None,
+            }
+        };
+        let mut times = s.splitn(2, '-');
+        match (times.next().and_then(&parse_time), times.next().and_then(&parse_time)) {
+            (Some(start), Some(end)) => Ok(MergeWindow { start: start, end: end }),
+            _ => Err(format!("bad merge window {}, expected HH:MM-HH:MM", s).into()),
+        }
+    }
+}
+
+
+/// The sealed files worth merging per the merge options of `option`, in
+/// the order of `candidates`. Files younger than `merge_min_file_age` are
+/// left alone. Of the others, a file is picked if at least
+/// `merge_fragmentation` percent of it is dead, if the dead bytes of all of
+/// them reach `merge_dead_bytes` and it has any, or if it is smaller than
+/// `merge_small_file_size` and there are more such files to coalesce it
+/// with.
+pub fn pick_files(candidates: &[Candidate], option: &BitcaskOptions, now: u32) -> Vec<u32> {
+    let candidates = candidates.iter()
+        .filter(|c| now.saturating_sub(c.created) as u64 >= option.merge_min_file_age.as_secs())
+        .collect::<Vec<&Candidate>>();
+    let size = |c: &Candidate| c.stats.live_bytes + c.stats.dead_bytes;
+    let dead_bytes = candidates.iter().map(|c| c.stats.dead_bytes).sum::<u64>();
+    let small_files = candidates.iter().filter(|c| size(c) < option.merge_small_file_size).count();
+    candidates.iter()
+        .filter(|c| {
+            let fragmented = c.stats.dead_bytes > 0 && c.stats.fragmentation() >= option.merge_fragmentation;
+            let dead = c.stats.dead_bytes > 0 && dead_bytes >= option.merge_dead_bytes;
+            let small = small_files > 1 && size(c) < option.merge_small_file_size;
+            fragmented || dead || small
+        })
+        .map(|c| c.stats.file_id)
+        .collect()
+}
+
+
+impl MergeJob {
+    pub fn new(inputs: Vec<Arc<DataFile>>, keep_tombstones: HashSet<u32>, entries: Arc<KeyDir>,
+               keyring: Option<Arc<Keyring>>, context: MergeContext) -> MergeJob {
+        MergeJob {
+            inputs: inputs,
+            keep_tombstones: keep_tombstones,
+            entries: entries,
+            keyring: keyring,
+            context: context,
+            output: None,
+            outputs: Vec::new(),
+            moves: Vec::new(),
+            tombstones: Vec::new(),
+        }
+    }
+
+    /// Ids of the files being merged.
+    pub fn inputs(&self) -> Vec<u32> {
+        self.inputs.iter().map(|data_file| data_file.file_id).collect()
+    }
+
+    /// Ids of the files written so far.
+    pub fn outputs(&self) -> &[u32] {
+        &self.outputs
+    }
+
+    /// Copies the live records of the inputs, and the tombstones still
+    /// needed, to new files. Each of those gets its id from `reserve`.
+    /// Values are written with the current compression and encryption key.
+    /// Reads and writes wait for the throttle of the store.
+    pub fn run(&mut self, reserve: &mut dyn FnMut() -> Result<u32>) -> Result<()> {
+        for data_file in self.inputs.clone() {
+            let mut hint_file = try!(HintFile::new(&self.context.path, data_file.file_id, None));
+            while let Some(hint_entry) = try!(hint_file.read_entry_strict()) {
+                let len = hint_entry.data_len();
+                try!(self.context.throttle.consume(len));
+                try!(self.copy(&data_file, hint_entry, reserve));
+                if let Ok(mut stats) = self.context.stats.lock() {
+                    stats.bytes_read += len;
+                }
+            }
+        }
+        if let Some((data, hint)) = self.output.take() {
+            try!(data.sync());
+            try!(hint.sync());
+        }
+        Ok(())
+    }
+
+    /// Takes the results of `run`.
+    pub fn into_outcome(self) -> MergeOutcome {
+        MergeOutcome {
+            inputs: self.inputs(),
+            outputs: self.outputs,
+            moves: self.moves,
+            tombstones: self.tombstones,
+        }
+    }
+
+    fn copy(&mut self, data_file: &DataFile, mut hint_entry: HintEntry, reserve: &mut dyn FnMut() -> Result<u32>)
+            -> Result<()> {
+        let file_id = data_file.file_id;
+        if hint_entry.flags & ENCRYPTED != 0 {
+            let keyring = try!(require_keyring(self.keyring.as_deref()));
+            hint_entry.key = try!(keyring.open(&hint_entry.key, b""));
+        }
+        let live = match self.entries.get(&hint_entry.key) {
+            Some(entry) => entry.file_id == file_id && entry.value_pos == hint_entry.value_pos,
+            None => false,
+        };
+
+        if live {
+            let mut value = vec![0; hint_entry.value_size as usize];
+            try!(data_file.read_exact(hint_entry.value_pos, &mut value));
+            if hint_entry.flags & ENCRYPTED != 0 {
+                let keyring = try!(require_keyring(self.keyring.as_deref()));
+                value = try!(keyring.open(&value, &hint_entry.key));
+            }
+            let mut value = try!(Compression::from_flags(hint_entry.flags).decompress(value));
+            if let Some(ref filter) = self.context.option.compaction_filter {
+                let metadata = RecordMetadata {
+                    timestamp: hint_entry.timestamp,
+                    file_id: file_id,
+                };
+                match filter.filter(&hint_entry.key, &value, &metadata) {
+                    FilterDecision::Keep => (),
+                    FilterDecision::Replace(new_value) => value = new_value,
+                    FilterDecision::Remove => return self.remove(hint_entry, file_id, reserve),
+                }
+            }
+            self.write(hint_entry.key, Some(value), hint_entry.timestamp, file_id, hint_entry.value_pos, reserve)
+        } else if self.keep_tombstones.contains(&file_id) && self.entries.get(&hint_entry.key).is_none()
+            && hint_entry.flags & TOMBSTONE != 0 {
+            self.write(hint_entry.key, None, hint_entry.timestamp, file_id, hint_entry.value_pos, reserve)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Drops a live record, with a tombstone if files before the output may
+    /// hold older records of its key.
+    fn remove(&mut self, hint_entry: HintEntry, file_id: u32, reserve: &mut dyn FnMut() -> Result<u32>) -> Result<()> {
+        if self.keep_tombstones.contains(&file_id) {
+            try!(self.write(hint_entry.key.clone(), None, hint_entry.timestamp, file_id, hint_entry.value_pos,
+                            reserve));
+        }
+        self.moves.push(Move {
+            key: hint_entry.key,
+            file_id: file_id,
+            value_pos: hint_entry.value_pos,
+            entry: None,
+            len: 0,
+        });
+        Ok(())
+    }
+
+    fn write(&mut self, key: Vec<u8>, value: Option<Vec<u8>>, timestamp: u32, from_file_id: u32, from_pos: u64,
+             reserve: &mut dyn FnMut() -> Result<u32>) -> Result<()> {
+        let is_delete = value.is_none();
+        let data_entry = try!(encode_record(&self.context.option, self.keyring.as_deref(), &key, value,
+                                            timestamp));
+        try!(self.context.throttle.consume(data_entry.encoded_len()));
+        if self.output.is_none() {
+            let output_id = try!(reserve());
+            self.outputs.push(output_id);
+            self.output = Some((try!(DataFile::new(&self.context.path, output_id, Some(0))),
+                                try!(HintFile::new(&self.context.path, output_id, Some(0)))));
+        }
+        let (output_id, value_pos) = match self.output {
+            Some((ref mut data, ref mut hint)) => {
+                let value_pos = try!(data.write(&data_entry));
+                try!(hint.write(&HintEntry {
+                    timestamp: timestamp,
+                    flags: data_entry.flags,
+                    key_size: data_entry.key_size,
+                    value_size: data_entry.value_size,
+                    value_pos: value_pos,
+                    key: data_entry.key.clone(),
+                }));
+                (data.file_id, value_pos)
+            },
+            None => unreachable!(),
+        };
+
+        let len = data_entry.encoded_len();
+        if is_delete {
+            self.tombstones.push((output_id, len));
+        } else {
+            self.moves.push(Move {
+                key: key,
+                file_id: from_file_id,
+                value_pos: from_pos,
+                entry: Some(Entry {
+                    timestamp: timestamp,
+                    flags: data_entry.flags,
+                    value_size: data_entry.value_size,
+                    value_pos: value_pos,
+                    file_id: output_id,
+                }),
+                len: len,
+            });
+        }
+        if let Ok(mut stats) = self.context.stats.lock() {
+            stats.bytes_written += len;
+        }
+
+        if value_pos >= self.context.option.file_size_limit {
+            if let Some((data, hint)) = self.output.take() {
+                try!(data.sync());
+                try!(hint.sync());
+            }
+        }
+        Ok(())
+    }
+}
+
+
+#[test]
+fn test_merge_window() {
+    let night = "02:00-05:00".parse::<MergeWindow>().unwrap();
+    assert_eq!(night, MergeWindow::new(2, 0, 5, 0));
+    assert!(night.contains(2 * 60));
+    assert!(night.contains(4 * 60 + 59));
+    assert!(!night.contains(5 * 60));
+    assert!(!night.contains(23 * 60));
+
+    let midnight = "23:30-00:30".parse::<MergeWindow>().unwrap();
+    assert!(midnight.contains(23 * 60 + 45));
+    assert!(midnight.contains(10));
+    assert!(!midnight.contains(12 * 60));
+
+    assert!("2-5".parse::<MergeWindow>().is_err());
+    assert!("02:00-25:00".parse::<MergeWindow>().is_err());
+}
+
+#[test]
+fn test_pick_files() {
+    use std::time::Duration;
+
+    let candidate = |file_id, live_bytes, dead_bytes, created| Candidate {
+        stats: FileStats { file_id: file_id, live_keys: 1, live_bytes: live_bytes, dead_keys: 1, dead_bytes: dead_bytes },
+        created: created,
+    };
+    let candidates = vec![
+        candidate(1, 100, 900, 0),
+        candidate(2, 900, 100, 0),
+        candidate(3, 10, 0, 0),
+        candidate(4, 10, 0, 50),
+        candidate(5, 5, 5, 100),
+    ];
+    let mut option = BitcaskOptions::default();
+    option.merge_fragmentation = 50;
+    option.merge_dead_bytes = 10000;
+    option.merge_small_file_size = 0;
+    assert_eq!(vec![1, 5], pick_files(&candidates, &option, 100));
+
+    option.merge_min_file_age = Duration::from_secs(60);
+    assert_eq!(vec![1], pick_files(&candidates, &option, 100));
+
+    option.merge_dead_bytes = 1000;
+    assert_eq!(vec![1, 2], pick_files(&candidates, &option, 100));
+
+    option.merge_small_file_size = 20;
+    assert_eq!(vec![1, 2, 3, 4], pick_files(&candidates, &option, 110));
+    assert_eq!(vec![1, 2], pick_files(&candidates, &option, 100));
+}
@@ -1,6 +1,27 @@
 pub mod bitcask;
+pub mod checkpoint;
+pub mod compression;
+pub mod crc;
 pub mod data_file;
+pub mod encryption;
+pub mod export;
+pub mod filter;
+pub mod header;
 pub mod hint_file;
+pub mod inspect;
+pub mod keydir;
+pub mod lock;
+pub mod manifest;
+pub mod merge;
+pub mod recovery;
+pub mod snapshot;
+pub mod stats;
+pub mod throttle;
+pub mod upgrade;
+pub mod varint;
 
 pub use self::bitcask::Bitcask;
 pub use self::bitcask::BitcaskOptions;
+pub use self::bitcask::WriteOp;
+pub use self::snapshot::Iter;
+pub use self::snapshot::Snapshot;
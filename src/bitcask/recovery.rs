@@ -0,0 +1,231 @@
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::BufReader;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::path::Path;
+
+use bitcask::data_file::DataEntry;
+use bitcask::header::FileHeader;
+use bitcask::header::DATA_MAGIC;
+use bitcask::header::HEADER_SIZE;
+use bitcask::header::HINT_MAGIC;
+use bitcask::hint_file::HintEntry;
+use bitcask::hint_file::HintFile;
+use ::error::ErrorKind;
+use ::error::Result;
+
+
+/// What `recover_tail` found at the end of the active files.
+#[derive(Debug, Default, PartialEq)]
+pub struct TailRecovery {
+    /// Bytes cut from the data file after its last complete record.
+    pub data_discarded: u64,
+    /// Bytes cut from the hint file after its last entry matching the data
+    /// file.
+    pub hint_discarded: u64,
+    /// Hint entries appended for data records the hint file was missing.
+    pub hints_added: usize,
+}
+
+
+/// Makes the data and hint file `file_id` in `path` consistent after a crash.
+///
+/// A process dying in the middle of an append leaves a partial record at the
+/// end of the data file, and possibly of the hint file, which is written
+/// right after it. Both files are cut back to their last complete record with
+/// a valid checksum, and the hint file is brought in line with the data
+/// file. With `truncate` false a torn tail is reported as
+/// `ErrorKind::TornTail` instead and nothing is changed.
+///
+/// Only a bad record running to the end of the data file can be torn; one
+/// followed by more data fails with `ErrorKind::CorruptRecord`, unless
+/// `salvage` allows cutting the file there too and losing what follows.
+pub fn recover_tail<P: AsRef<Path>>(path: P, file_id: u32, truncate: bool, salvage: bool) -> Result<TailRecovery> {
+    let data_path = path.as_ref().join(format!("{}.data", file_id));
+    let hint_path = path.as_ref().join(format!("{}.hint", file_id));
+    let mut recovery = TailRecovery::default();
+    if !data_path.exists() {
+        return Ok(recovery);
+    }
+
+    let (data_valid, data_len, records) = try!(scan_data_file(&data_path, salvage));
+    if data_valid < data_len {
+        try!(discard_tail(&data_path, data_valid, data_len, truncate));
+        recovery.data_discarded = data_len - data_valid;
+    }
+
+    let mut hinted = 0;
+    if hint_path.exists() {
+        let (hint_valid, hint_len, count) = try!(scan_hint_file(&hint_path, &records));
+        if hint_valid < hint_len {
+            try!(discard_tail(&hint_path, hint_valid, hint_len, truncate));
+            recovery.hint_discarded = hint_len - hint_valid;
+        }
+        hinted = count;
+    }
+    if hinted < records.len() {
+        let mut hint_file = try!(HintFile::new(path.as_ref(), file_id, Some(0)));
+        try!(hint_file.write_entries(&records[hinted..]));
+        recovery.hints_added = records.len() - hinted;
+    }
+
+    Ok(recovery)
+}
+
+
+/// Returns the length of the valid prefix of a data file, its actual length
+/// and a hint entry for every complete record. Unless `salvage`, a bad
+/// record that doesn't run to the end of the file is an error.
+pub fn scan_data_file(file_path: &Path, salvage: bool) -> Result<(u64, u64, Vec<HintEntry>)> {
+    let file = try!(File::open(file_path));
+    let len = try!(file.metadata()).len();
+    if len < HEADER_SIZE {
+        // Died before the header made it to disk.
+        return Ok((0, len, Vec::new()));
+    }
+
+    let mut reader = BufReader::new(file);
+    try!(FileHeader::read_from(&mut reader, file_path, DATA_MAGIC));
+    let mut valid = HEADER_SIZE;
+    let mut records = Vec::new();
+    while let Ok(entry) = DataEntry::decode(&mut reader) {
+        let value_pos = valid + entry.value_offset();
+        valid += entry.encoded_len();
+        records.push(HintEntry {
+            timestamp: entry.timestamp,
+            flags: entry.flags,
+            key_size: entry.key_size,
+            value_size: entry.value_size,
+            value_pos: value_pos,
+            key: entry.key,
+        });
+    }
+    // Decoding stops at the end of the file or of the bad record, since
+    // its checksum is only checked once it is read whole.
+    if valid < len && !salvage && try!(reader.seek(SeekFrom::Current(0))) < len {
+        return Err(ErrorKind::CorruptRecord(file_path.to_string_lossy().into_owned(), valid).into());
+    }
+    Ok((valid, len, records))
+}
+
+
+/// Returns the length of the prefix of a hint file whose entries match
+/// `records` one by one, its actual length and the number of entries in it.
+pub fn scan_hint_file(file_path: &Path, records: &[HintEntry]) -> Result<(u64, u64, usize)> {
+    let file = try!(File::open(file_path));
+    let len = try!(file.metadata()).len();
+    if len < HEADER_SIZE {
+        return Ok((0, len, 0));
+    }
+
+    let mut reader = BufReader::new(file);
+    try!(FileHeader::read_from(&mut reader, file_path, HINT_MAGIC));
+    let mut valid = HEADER_SIZE;
+    let mut count = 0;
+    while let Ok(hint_entry) = HintEntry::decode(&mut reader) {
+        match records.get(count) {
+            Some(record) if record.value_pos == hint_entry.value_pos && record.value_size == hint_entry.value_size
+                && record.flags == hint_entry.flags && record.key == hint_entry.key => (),
+            _ => break,
+        }
+        valid += hint_entry.encoded_len();
+        count += 1;
+    }
+    Ok((valid, len, count))
+}
+
+
+fn discard_tail(file_path: &Path, valid: u64, len: u64, truncate: bool) -> Result<()> {
+    let name = file_path.to_string_lossy().into_owned();
+    if !truncate {
+        return Err(ErrorKind::TornTail(name, len - valid).into());
+    }
+    let file = try!(OpenOptions::new().write(true).open(file_path));
+    try!(file.set_len(valid));
+    try!(file.sync_all());
+    println!("discarded {} bytes from the torn tail of {}", len - valid, name);
+    Ok(())
+}
+
+
+#[test]
+fn test_recover_tail() {
+    use std::fs;
+    use std::io::Write;
+    use bitcask::data_file::DataFile;
+
+    let dir = ::bitcask::bitcask::test_dir("recovery");
+    let path = dir.0.clone();
+
+    let entry = |key: &[u8]| DataEntry {
+        crc: 0,
+        timestamp: 1,
+        flags: 0,
+        key_size: key.len() as u32,
+        value_size: 5,
+        key: key.to_vec(),
+        value: b"value".to_vec(),
+    };
+    let positions = {
+        let mut data_file = DataFile::new(&path, 0, Some(0)).unwrap();
+        data_file.write_entries(&[entry(b"a"), entry(b"b")]).unwrap()
+    };
+    {
+        let mut hint_file = HintFile::new(&path, 0, Some(0)).unwrap();
+        hint_file.write(&HintEntry {
+            timestamp: 1,
+            flags: 0,
+            key_size: 1,
+            value_size: 5,
+            value_pos: positions[0],
+            key: b"a".to_vec(),
+        }).unwrap();
+    }
+    let data_path = path.join("0.data");
+    let data_len = fs::metadata(&data_path).unwrap().len();
+    // Half of a third record.
+    let mut torn = Vec::new();
+    entry(b"c").encode(&mut torn).unwrap();
+    OpenOptions::new().append(true).open(&data_path).unwrap().write_all(&torn[..7]).unwrap();
+
+    match recover_tail(&path, 0, false, false) {
+        Err(e) => match *e.kind() {
+            ErrorKind::TornTail(_, 7) => (),
+            _ => panic!("unexpected error {:?}", e),
+        },
+        Ok(r) => panic!("torn tail accepted: {:?}", r),
+    }
+    assert_eq!(fs::metadata(&data_path).unwrap().len(), data_len + 7);
+
+    let recovery = recover_tail(&path, 0, true, false).unwrap();
+    assert_eq!(recovery, TailRecovery { data_discarded: 7, hint_discarded: 0, hints_added: 1 });
+    assert_eq!(fs::metadata(&data_path).unwrap().len(), data_len);
+
+    let hints = HintFile::new(&path, 0, None).unwrap().collect::<Vec<HintEntry>>();
+    assert_eq!(hints.len(), 2);
+    assert_eq!(hints[1].value_pos, positions[1]);
+    assert_eq!(recover_tail(&path, 0, true, false).unwrap(), TailRecovery::default());
+
+    // A damaged record with another one after it.
+    let mut record = Vec::new();
+    entry(b"d").encode(&mut record).unwrap();
+    let record_len = record.len() as u64;
+    OpenOptions::new().append(true).open(&data_path).unwrap().write_all(&record).unwrap();
+    {
+        let mut file = OpenOptions::new().write(true).open(&data_path).unwrap();
+        file.seek(SeekFrom::Start(positions[1])).unwrap();
+        file.write_all(b"X").unwrap();
+    }
+    match recover_tail(&path, 0, true, false) {
+        Err(e) => match *e.kind() {
+            ErrorKind::CorruptRecord(_, offset) if offset == data_len - record_len => (),
+            _ => panic!("unexpected error {:?}", e),
+        },
+        Ok(r) => panic!("corrupt record cut: {:?}", r),
+    }
+    assert_eq!(fs::metadata(&data_path).unwrap().len(), data_len + record_len);
+    let recovery = recover_tail(&path, 0, true, true).unwrap();
+    assert_eq!(recovery.data_discarded, 2 * record_len);
+    assert_eq!(fs::metadata(&data_path).unwrap().len(), data_len - record_len);
+}
@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::ops::Bound;
+use std::ops::RangeBounds;
+use std::sync::Arc;
+use std::vec;
+
+use bitcask::compression::Compression;
+use bitcask::data_file::DataFile;
+use bitcask::encryption::ENCRYPTED;
+use bitcask::encryption::Keyring;
+use bitcask::export::ExportFormat;
+use bitcask::export::ExportRecord;
+use bitcask::export::write_header;
+use bitcask::export::write_record;
+use bitcask::keydir::KeyDir;
+use bitcask::keydir::prefix_range;
+use ::error::ErrorKind;
+use ::error::Result;
+
+
+/// A point-in-time view of the store, from `Bitcask::snapshot`.
+///
+/// It shares the keydir with the store, which copies it on its next write
+/// rather than changing the snapshot's, and holds the data files the keydir
+/// points into, which keeps them from being deleted by a merge. It is
+/// independent of the store otherwise, so writes carry on while it is used.
+#[derive(Clone)]
+pub struct Snapshot {
+    entries: Arc<KeyDir>,
+    data_files: HashMap<u32, Arc<DataFile>>,
+    keyring: Option<Arc<Keyring>>,
+}
+
+
+/// Keys of a scan along with their values, from a snapshot taken when the
+/// scan starts. `keys` gives just the keys.
+///
+/// The keys are copied out of the keydir when the scan starts, so a scan
+/// of the whole store costs memory for every key up front; values are read
+/// as the scan goes.
+pub struct Iter {
+    snapshot: Snapshot,
+    keys: vec::IntoIter<Vec<u8>>,
+}
+
+
+impl Snapshot {
+    pub fn new(entries: Arc<KeyDir>, data_files: HashMap<u32, Arc<DataFile>>,
+               keyring: Option<Arc<Keyring>>) -> Snapshot {
+        Snapshot {
+            entries: entries,
+            data_files: data_files,
+            keyring: keyring,
+        }
+    }
+
+    pub fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>> {
+        read_value(&self.entries, &self.data_files, self.keyring.as_deref(), key.as_ref())
+    }
+
+    /// Every key, in order if the keydir is ordered. They are all copied
+    /// into a `Vec` first.
+    pub fn keys(&self) -> vec::IntoIter<Vec<u8>> {
+        self.entries.keys().into_iter()
+    }
+
+    /// Scans every key and value, in order if the keydir is ordered. Every
+    /// key is copied first, see `Iter`.
+    pub fn iter(&self) -> Iter {
+        self.scan(self.entries.keys())
+    }
+
+    /// Scans the keys in `range`, in order.
+    pub fn range<K: AsRef<[u8]>, R: RangeBounds<K>>(&self, range: R) -> Iter {
+        let keys = self.entries.keys_between(bound_as_slice(range.start_bound()),
+                                             bound_as_slice(range.end_bound()));
+        self.scan(keys)
+    }
+
+    /// Scans the keys starting with `prefix`, in order.
+    pub fn prefix<P: AsRef<[u8]>>(&self, prefix: P) -> Iter {
+        let keys = {
+            let (start, end) = prefix_range(prefix.as_ref());
+            let end = match end {
+                Bound::Excluded(ref e) => Bound::Excluded(e.as_slice()),
+                _ => Bound::Unbounded,
+            };
+            self.entries.keys_between(start, end)
+        };
+        self.scan(keys)
+    }
+
+    /// Writes every key and value to `out`, see `Bitcask::export`.
+    pub fn export<W: Write>(&self, out: &mut W, format: ExportFormat) -> Result<usize> {
+        try!(write_header(out, format));
+        let mut count = 0;
+        for key in self.entries.keys() {
+            let timestamp = match self.entries.get(&key) {
+                Some(entry) => entry.timestamp,
+                None => continue,
+            };
+            let value = match try!(self.get(&key)) {
+                Some(value) => value,
+                None => continue,
+            };
+            try!(write_record(out, format, &ExportRecord {
+                key: key,
+                value: value,
+                timestamp: timestamp,
+                flags: 0,
+                ttl: 0,
+            }));
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// The ids of the data files the snapshot holds.
+    pub fn file_ids(&self) -> Vec<u32> {
+        let mut file_ids = self.data_files.keys().cloned().collect::<Vec<u32>>();
+        file_ids.sort();
+        file_ids
+    }
+
+    fn scan(&self, keys: Vec<Vec<u8>>) -> Iter {
+        Iter {
+            snapshot: self.clone(),
+            keys: keys.into_iter(),
+        }
+    }
+}
+
+
+impl Iter {
+    /// The keys of the scan, without reading their values.
+    pub fn keys(self) -> vec::IntoIter<Vec<u8>> {
+        self.keys
+    }
+}
+
+
+impl Iterator for Iter {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Result<(Vec<u8>, Vec<u8>)>> {
+        for key in &mut self.keys {
+            match self.snapshot.get(&key) {
+                Ok(Some(value)) => return Some(Ok((key, value))),
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        None
+    }
+}
+
+
+/// Looks `key` up in `entries` and reads its value from `data_files`,
+/// decrypting and decompressing it as its flags say.
+pub fn read_value(entries: &KeyDir, data_files: &HashMap<u32, Arc<DataFile>>, keyring: Option<&Keyring>,
+                  key: &[u8]) -> Result<Option<Vec<u8>>> {
+    let entry = match entries.get(key) {
+        None => return Ok(None),
+        Some(e) => e
+    };
+    let data_file = match data_files.get(&entry.file_id) {
+        None => return Err(ErrorKind::MissingFile(entry.file_id).into()),
+        Some(data_file) => data_file
+    };
+    let mut value = vec![0; entry.value_size as usize];
+    try!(data_file.read_exact(entry.value_pos, value.as_mut_slice()));
+    if entry.flags & ENCRYPTED != 0 {
+        value = try!(try!(require_keyring(keyring)).open(&value, key));
+    }
+    Compression::from_flags(entry.flags).decompress(value).map(Some)
+}
+
+
+pub fn require_keyring(keyring: Option<&Keyring>) -> Result<&Keyring> {
+    keyring.ok_or_else(|| ErrorKind::Encryption("encrypted records need a key file".to_owned()).into())
+}
+
+
+fn bound_as_slice<K: AsRef<[u8]>>(bound: Bound<&K>) -> Bound<&[u8]> {
+    match bound {
+        Bound::Included(k) => Bound::Included(k.as_ref()),
+        Bound::Excluded(k) => Bound::Excluded(k.as_ref()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+
+use bitcask::data_file::record_len;
+use bitcask::encryption::ENCRYPTED;
+use bitcask::encryption::sealed_len;
+use bitcask::keydir::Entry;
+
+
+/// How much of a data file is still in use, from `Bitcask::file_stats`.
+/// Records are live while the keydir points at them; overwritten and
+/// deleted ones, and tombstones, are dead until a merge drops them.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct FileStats {
+    pub file_id: u32,
+    pub live_keys: u64,
+    pub live_bytes: u64,
+    pub dead_keys: u64,
+    pub dead_bytes: u64,
+}
+
+
+impl FileStats {
+    pub fn new(file_id: u32) -> FileStats {
+        FileStats {
+            file_id: file_id,
+            ..Default::default()
+        }
+    }
+
+    /// Share of the file's record bytes that are dead, in percent.
+    pub fn fragmentation(&self) -> u64 {
+        let total = self.live_bytes + self.dead_bytes;
+        if total == 0 {
+            return 0;
+        }
+        self.dead_bytes * 100 / total
+    }
+}
+
+
+/// `FileStats` of every data file, kept up to date as records are written
+/// and, on open, as hints are applied to the keydir.
+#[derive(Debug, Default)]
+pub struct StatsTable {
+    files: HashMap<u32, FileStats>,
+}
+
+
+impl StatsTable {
+    pub fn new() -> StatsTable {
+        Default::default()
+    }
+
+    /// Counts a record of `len` bytes added to `file_id`, which is live
+    /// unless it is a tombstone.
+    pub fn add(&mut self, file_id: u32, len: u64, live: bool) {
+        let stats = self.files.entry(file_id).or_insert_with(|| FileStats::new(file_id));
+        if live {
+            stats.live_keys += 1;
+            stats.live_bytes += len;
+        } else {
+            stats.dead_keys += 1;
+            stats.dead_bytes += len;
+        }
+    }
+
+    /// Counts the record of `entry`, a key of `key_len` bytes, as dead now
+    /// that a newer one replaces or deletes it.
+    pub fn supersede(&mut self, key_len: usize, entry: &Entry) {
+        let key_size = if entry.flags & ENCRYPTED != 0 { sealed_len(key_len) } else { key_len };
+        let len = record_len(key_size as u64, entry.value_size);
+        let stats = self.files.entry(entry.file_id).or_insert_with(|| FileStats::new(entry.file_id));
+        stats.live_keys = stats.live_keys.saturating_sub(1);
+        stats.live_bytes = stats.live_bytes.saturating_sub(len);
+        stats.dead_keys += 1;
+        stats.dead_bytes += len;
+    }
+
+    /// Forgets `file_id`, once a merge has replaced it.
+    pub fn remove(&mut self, file_id: u32) {
+        self.files.remove(&file_id);
+    }
+
+    /// The stats of `file_id`, all zero if nothing was counted in it.
+    pub fn get(&self, file_id: u32) -> FileStats {
+        self.files.get(&file_id).cloned().unwrap_or_else(|| FileStats::new(file_id))
+    }
+}
+
+
+#[test]
+fn test_stats_table() {
+    let mut table = StatsTable::new();
+    table.add(1, 10, true);
+    table.add(1, 7, false);
+    let entry = Entry { timestamp: 0, flags: ENCRYPTED, value_size: 5, value_pos: 0, file_id: 1 };
+    table.add(1, record_len(sealed_len(3) as u64, 5), true);
+    table.supersede(3, &entry);
+    assert_eq!(table.get(1), FileStats { file_id: 1, live_keys: 1, live_bytes: 10, dead_keys: 2, dead_bytes: 7 + 63 });
+    assert_eq!(table.get(2), FileStats::new(2));
+}
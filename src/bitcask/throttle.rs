@@ -0,0 +1,187 @@
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use ::error::Result;
+
+
+/// Limits the I/O of merges to a number of bytes per second with a token
+/// bucket holding up to a second worth of bytes, and pauses them on request.
+/// It is shared between the store and its merge jobs, so either can be
+/// changed while a merge runs. Once cancelled it fails every merge, so that
+/// a shutdown doesn't wait for a paused or slow one.
+#[derive(Debug)]
+pub struct Throttle {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+
+#[derive(Debug)]
+struct State {
+    /// Bytes per second, 0 for no limit.
+    rate: u64,
+    /// May go negative: a large read or write is let through at once and
+    /// the next ones wait until it is paid for.
+    tokens: f64,
+    last: Instant,
+    paused: bool,
+    cancelled: bool,
+}
+
+
+impl Throttle {
+    pub fn new(rate: u64) -> Throttle {
+        Throttle {
+            state: Mutex::new(State {
+                rate: rate,
+                tokens: rate as f64,
+                last: Instant::now(),
+                paused: false,
+                cancelled: false,
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// The limit in bytes per second, 0 for none.
+    pub fn rate(&self) -> u64 {
+        self.state.lock().map(|state| state.rate).unwrap_or(0)
+    }
+
+    pub fn set_rate(&self, rate: u64) {
+        if let Ok(mut state) = self.state.lock() {
+            state.refill();
+            state.rate = rate;
+            state.tokens = state.tokens.min(rate as f64);
+        }
+        self.changed.notify_all();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.state.lock().map(|state| state.paused).unwrap_or(false)
+    }
+
+    /// Makes `consume` block until `resume`.
+    pub fn pause(&self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.paused = true;
+        }
+    }
+
+    pub fn resume(&self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.paused = false;
+        }
+        self.changed.notify_all();
+    }
+
+    /// Makes `consume` fail from now on, waking up those waiting in it.
+    pub fn cancel(&self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.cancelled = true;
+        }
+        self.changed.notify_all();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.state.lock().map(|state| state.cancelled).unwrap_or(false)
+    }
+
+    /// Waits until `bytes` may be read or written. Fails if the throttle is
+    /// cancelled meanwhile.
+    pub fn consume(&self, bytes: u64) -> Result<()> {
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(_) => return Ok(()),
+        };
+        loop {
+            if state.cancelled {
+                return Err("merge cancelled".into());
+            }
+            if state.paused {
+                state = match self.changed.wait(state) {
+                    Ok(state) => state,
+                    Err(_) => return Ok(()),
+                };
+                continue;
+            }
+            state.refill();
+            if state.rate == 0 || state.tokens >= 0.0 {
+                break;
+            }
+            let wait = Duration::from_secs_f64(-state.tokens / state.rate as f64);
+            state = match self.changed.wait_timeout(state, wait) {
+                Ok((state, _)) => state,
+                Err(_) => return Ok(()),
+            };
+        }
+        if state.rate != 0 {
+            state.tokens -= bytes as f64;
+        }
+        Ok(())
+    }
+}
+
+
+impl State {
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+    }
+}
+
+
+#[test]
+fn test_throttle() {
+    use std::sync::Arc;
+    use std::thread;
+
+    let throttle = Throttle::new(1000);
+    let tokens = |throttle: &Throttle| throttle.state.lock().unwrap().tokens;
+    // A full bucket lets a large write through at once, and the debt is
+    // paid off as time passes.
+    throttle.consume(1500).unwrap();
+    assert_eq!(-500.0, tokens(&throttle));
+    {
+        let mut state = throttle.state.lock().unwrap();
+        state.last -= Duration::from_millis(600);
+        state.refill();
+        assert!(state.tokens >= 100.0 && state.tokens <= 1000.0, "{}", state.tokens);
+        state.last -= Duration::from_secs(10);
+        state.refill();
+        assert_eq!(1000.0, state.tokens);
+    }
+    throttle.set_rate(400);
+    assert_eq!(400.0, tokens(&throttle));
+    throttle.consume(100).unwrap();
+    assert_eq!(300.0, tokens(&throttle));
+
+    throttle.set_rate(0);
+    throttle.consume(1 << 30).unwrap();
+    assert_eq!(0, throttle.rate());
+    assert!(tokens(&throttle) >= 0.0);
+
+    let throttle = Arc::new(throttle);
+    let consumer = |throttle: &Arc<Throttle>| {
+        let throttle = throttle.clone();
+        thread::spawn(move || throttle.consume(1))
+    };
+    throttle.pause();
+    assert!(throttle.is_paused());
+    let paused = consumer(&throttle);
+    throttle.resume();
+    assert!(paused.join().unwrap().is_ok());
+
+    // Cancelling wakes up a paused merge, and outlasts `resume`.
+    throttle.pause();
+    let paused = consumer(&throttle);
+    throttle.cancel();
+    assert!(paused.join().unwrap().is_err());
+    throttle.resume();
+    assert!(throttle.is_cancelled());
+    assert!(throttle.consume(1).is_err());
+}
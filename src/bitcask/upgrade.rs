@@ -0,0 +1,329 @@
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use byteorder::ReadBytesExt;
+use byteorder::LittleEndian;
+
+use bitcask::data_file::DataEntry;
+use bitcask::data_file::TOMBSTONE;
+use bitcask::crc::CrcReader;
+use bitcask::data_file::read_bytes;
+use bitcask::header::FileHeader;
+use bitcask::header::file_version;
+use bitcask::header::DATA_MAGIC;
+use bitcask::header::FORMAT_VERSION;
+use bitcask::header::HEADER_SIZE;
+use bitcask::header::HINT_MAGIC;
+use bitcask::hint_file::HintEntry;
+use bitcask::lock::DirLock;
+use bitcask::varint::read_varint;
+use ::error::ErrorKind;
+use ::error::Result;
+
+
+/// What `upgrade` did, or would do in a dry run.
+#[derive(Debug, Default)]
+pub struct UpgradeReport {
+    /// Files rewritten in the current format.
+    pub upgraded: Vec<PathBuf>,
+    /// Files already in the current format.
+    pub current: Vec<PathBuf>,
+}
+
+
+/// Converts the data and hint files in `path` from older format versions,
+/// including the headerless version 0, to the current one in place. With
+/// `dry_run` nothing is written.
+///
+/// Records are decoded with the layout of their version and written again,
+/// which moves them, so the hint file of every converted data file is
+/// regenerated from it. Each file is written next to the original and
+/// renamed over it, data file first, so an interrupted run can simply be
+/// restarted. Like `Bitcask::new`, it takes the directory lock.
+pub fn upgrade<P: AsRef<Path>>(path: P, dry_run: bool) -> Result<UpgradeReport> {
+    let _lock = try!(DirLock::acquire(path.as_ref()));
+    let mut report = UpgradeReport::default();
+    let mut file_ids = Vec::new();
+    for file in try!(path.as_ref().read_dir()) {
+        let file_path = try!(file).path();
+        if file_path.extension().and_then(|e| e.to_str()) != Some("data") {
+            continue;
+        }
+        match file_path.file_stem().and_then(|s| s.to_str()).map(|s| s.parse::<u32>()) {
+            Some(Ok(i)) => file_ids.push(i),
+            _ => continue
+        };
+    }
+    file_ids.sort();
+
+    for file_id in file_ids {
+        let data_path = path.as_ref().join(format!("{}.data", file_id));
+        let hint_path = path.as_ref().join(format!("{}.hint", file_id));
+        let data_version = try!(file_version(&data_path, DATA_MAGIC));
+        let hint_version = if hint_path.exists() {
+            Some(try!(file_version(&hint_path, HINT_MAGIC)))
+        } else {
+            None
+        };
+        for &(version, ref file_path) in &[(Some(data_version), &data_path), (hint_version, &hint_path)] {
+            if let Some(version) = version {
+                if version > FORMAT_VERSION {
+                    return Err(ErrorKind::UnsupportedVersion(file_path.to_string_lossy().into_owned(), version).into());
+                }
+            }
+        }
+
+        if data_version < FORMAT_VERSION {
+            let entries = try!(read_legacy_data_file(&data_path, data_version));
+            if !dry_run {
+                try!(rewrite(&data_path, &hint_path, file_id, &entries));
+            }
+            report.upgraded.push(data_path);
+            report.upgraded.push(hint_path);
+        } else if hint_version.map_or(false, |v| v < FORMAT_VERSION) {
+            // An earlier run got as far as renaming the data file.
+            let entries = try!(read_legacy_data_file(&data_path, data_version));
+            if !dry_run {
+                try!(replace_file(&hint_path, &try!(encode_files(file_id, &entries)).1));
+            }
+            report.current.push(data_path);
+            report.upgraded.push(hint_path);
+        } else {
+            report.current.push(data_path);
+            if hint_version.is_some() {
+                report.current.push(hint_path);
+            }
+        }
+    }
+
+    if !dry_run && !report.upgraded.is_empty() {
+        try!(try!(File::open(path.as_ref())).sync_all());
+    }
+    Ok(report)
+}
+
+
+/// Reads every record of a data file written with format `version`. A
+/// record cut short by the end of the file is dropped, as the old readers
+/// did; any other bad record fails the upgrade, which leaves the file as it
+/// is. Before version 4 a value of four zero bytes was a delete, so those
+/// records get the tombstone flag.
+fn read_legacy_data_file(file_path: &Path, version: u16) -> Result<Vec<DataEntry>> {
+    let mut file = try!(File::open(file_path));
+    let len = try!(file.metadata()).len();
+    if version >= 1 {
+        try!(file.seek(SeekFrom::Start(HEADER_SIZE)));
+    }
+    let mut reader = BufReader::new(file);
+    let mut entries = Vec::new();
+    loop {
+        let start = try!(reader.stream_position());
+        if start >= len {
+            return Ok(entries);
+        }
+        let entry = if version >= 4 {
+            DataEntry::decode(&mut reader)
+        } else {
+            read_legacy_data_entry(&mut reader, version)
+        };
+        match entry {
+            Ok(mut entry) => {
+                if version < 4 && entry.value == [0, 0, 0, 0] {
+                    entry.flags |= TOMBSTONE;
+                }
+                entries.push(entry);
+            },
+            Err(ref e) if e.kind() == ::std::io::ErrorKind::UnexpectedEof
+                && try!(reader.stream_position()) >= len => return Ok(entries),
+            Err(_) => return Err(ErrorKind::CorruptRecord(file_path.to_string_lossy().into_owned(), start).into()),
+        }
+    }
+}
+
+
+/// Versions before 3 had an unused two byte checksum field. Versions 0 and 1
+/// stored the key size in one byte and the value size in four, version 2 and
+/// 3 as varints, and version 3 checksums them like now. Version 3 had no
+/// flags, its values are uncompressed. Version 4 records are laid out as they are now.
+fn read_legacy_data_entry<R: Read>(reader: &mut R, version: u16) -> ::std::io::Result<DataEntry> {
+    let crc = if version >= 3 {
+        Some(try!(reader.read_u32::<LittleEndian>()))
+    } else {
+        try!(reader.read_u16::<LittleEndian>());
+        None
+    };
+    let mut reader = CrcReader::new(reader);
+    let timestamp = try!(reader.read_u32::<LittleEndian>());
+    let (key_size, value_size) = if version >= 2 {
+        (try!(read_varint(&mut reader)), try!(read_varint(&mut reader)))
+    } else {
+        (try!(reader.read_u8()) as u64, try!(reader.read_u32::<LittleEndian>()) as u64)
+    };
+    let key = try!(read_bytes(&mut reader, key_size));
+    let value = try!(read_bytes(&mut reader, value_size));
+    if crc.map_or(false, |crc| reader.crc.finish() != crc) {
+        return Err(::std::io::Error::new(::std::io::ErrorKind::InvalidData, "checksum mismatch"));
+    }
+
+    Ok(DataEntry {
+        crc: 0,
+        timestamp: timestamp,
+        flags: 0,
+        key_size: key_size as u32,
+        value_size: value_size,
+        key: key,
+        value: value,
+    })
+}
+
+
+/// Encodes `entries` as a data file and the matching hint file.
+fn encode_files(file_id: u32, entries: &[DataEntry]) -> Result<(Vec<u8>, Vec<u8>)> {
+    let mut data = Vec::new();
+    let mut hint = Vec::new();
+    try!(FileHeader::new(DATA_MAGIC, file_id).write_to(&mut data));
+    try!(FileHeader::new(HINT_MAGIC, file_id).write_to(&mut hint));
+    for entry in entries {
+        let value_pos = data.len() as u64 + entry.value_offset();
+        try!(entry.encode(&mut data));
+        let hint_entry = HintEntry {
+            timestamp: entry.timestamp,
+            flags: entry.flags,
+            key_size: entry.key_size,
+            value_size: entry.value_size,
+            value_pos: value_pos,
+            key: entry.key.clone(),
+        };
+        try!(hint_entry.encode(&mut hint));
+    }
+    Ok((data, hint))
+}
+
+
+fn rewrite(data_path: &Path, hint_path: &Path, file_id: u32, entries: &[DataEntry]) -> Result<()> {
+    let (data, hint) = try!(encode_files(file_id, entries));
+    try!(replace_file(data_path, &data));
+    replace_file(hint_path, &hint)
+}
+
+
+fn replace_file(file_path: &Path, contents: &[u8]) -> Result<()> {
+    let tmp_path = file_path.with_extension(format!("{}.upgrade",
+        file_path.extension().and_then(|e| e.to_str()).unwrap_or("")));
+    {
+        let mut tmp = try!(OpenOptions::new().write(true).create(true).truncate(true).open(&tmp_path));
+        try!(tmp.write_all(contents));
+        try!(tmp.sync_all());
+    }
+    try!(fs::rename(&tmp_path, file_path));
+    Ok(())
+}
+
+
+#[cfg(test)]
+fn legacy_v3(key: u8, value: &[u8]) -> Vec<u8> {
+    use byteorder::WriteBytesExt;
+    use bitcask::crc::crc32;
+
+    let mut record = vec![0; 4];
+    record.write_u32::<LittleEndian>(1).unwrap();
+    record.extend_from_slice(&[1, value.len() as u8, key]);
+    record.extend_from_slice(value);
+    let crc = crc32(&record[4..]);
+    (&mut record[..4]).write_u32::<LittleEndian>(crc).unwrap();
+    record
+}
+
+#[test]
+fn test_upgrade_legacy() {
+    use byteorder::WriteBytesExt;
+    use bitcask::Bitcask;
+    use bitcask::BitcaskOptions;
+
+    let dir = ::bitcask::bitcask::test_dir("upgrade");
+    let path = dir.0.clone();
+
+    // One record per file in the version 0/1 layout, hint files left as is
+    // since they are regenerated anyway.
+    let legacy = |key: u8, value: u8| {
+        let mut data = Vec::new();
+        data.write_u16::<LittleEndian>(0).unwrap();
+        data.write_u32::<LittleEndian>(1).unwrap();
+        data.push(1);
+        data.write_u32::<LittleEndian>(1).unwrap();
+        data.push(key);
+        data.push(value);
+        data
+    };
+    File::create(path.join("0.data")).unwrap().write_all(&legacy(b'a', b'1')).unwrap();
+    File::create(path.join("0.hint")).unwrap().write_all(b"stale").unwrap();
+    let mut v1 = Vec::new();
+    FileHeader { magic: DATA_MAGIC, version: 1, file_id: 1, created: 0 }.write_to(&mut v1).unwrap();
+    v1.extend_from_slice(&legacy(b'b', b'2'));
+    File::create(path.join("1.data")).unwrap().write_all(&v1).unwrap();
+    // Version 3, with a checksum but no flags.
+    let mut v3 = Vec::new();
+    FileHeader { magic: DATA_MAGIC, version: 3, file_id: 2, created: 0 }.write_to(&mut v3).unwrap();
+    v3.extend_from_slice(&legacy_v3(b'c', b"3"));
+    File::create(path.join("2.data")).unwrap().write_all(&v3).unwrap();
+    // Deletes were told apart by their value.
+    let mut deletes = Vec::new();
+    FileHeader { magic: DATA_MAGIC, version: 3, file_id: 3, created: 0 }.write_to(&mut deletes).unwrap();
+    deletes.extend_from_slice(&legacy_v3(b'a', &[0; 4]));
+    File::create(path.join("3.data")).unwrap().write_all(&deletes).unwrap();
+
+    assert!(Bitcask::new(path.to_string_lossy().into_owned(), BitcaskOptions::default()).is_err());
+
+    let report = upgrade(&path, true).unwrap();
+    assert_eq!(report.upgraded.len(), 8);
+    assert_eq!(file_version(&path.join("0.data"), DATA_MAGIC).unwrap(), 0);
+
+    let report = upgrade(&path, false).unwrap();
+    assert_eq!(report.upgraded.len(), 8);
+    let report = upgrade(&path, false).unwrap();
+    assert_eq!(report.current.len(), 8);
+
+    let mut bitcask = Bitcask::new(path.to_string_lossy().into_owned(), BitcaskOptions::default()).unwrap();
+    assert_eq!(None, bitcask.get("a").unwrap());
+    assert_eq!(b"2".to_vec(), bitcask.get("b").unwrap().unwrap());
+    assert_eq!(b"3".to_vec(), bitcask.get("c").unwrap().unwrap());
+}
+
+#[test]
+fn test_upgrade_corrupt() {
+    let dir = ::bitcask::bitcask::test_dir("upgrade-corrupt");
+    let path = dir.0.clone();
+
+    let mut v3 = Vec::new();
+    FileHeader { magic: DATA_MAGIC, version: 3, file_id: 0, created: 0 }.write_to(&mut v3).unwrap();
+    for &key in b"abc" {
+        v3.extend_from_slice(&legacy_v3(key, b"1"));
+    }
+    // A torn last record is dropped, as the old readers did.
+    let torn = v3.len() - 2;
+    File::create(path.join("0.data")).unwrap().write_all(&v3[..torn]).unwrap();
+    assert_eq!(2, read_legacy_data_file(&path.join("0.data"), 3).unwrap().len());
+
+    // A damaged one in the middle fails the upgrade, leaving the file be.
+    let middle = HEADER_SIZE as usize + 12 + 11;
+    v3[middle] = b'2';
+    File::create(path.join("0.data")).unwrap().write_all(&v3).unwrap();
+    match upgrade(&path, false) {
+        Err(e) => match *e.kind() {
+            ErrorKind::CorruptRecord(_, offset) if offset == HEADER_SIZE + 12 => (),
+            _ => panic!("unexpected error {:?}", e),
+        },
+        Ok(r) => panic!("corrupt file upgraded: {:?}", r),
+    }
+    let mut contents = Vec::new();
+    File::open(path.join("0.data")).unwrap().read_to_end(&mut contents).unwrap();
+    assert_eq!(v3, contents);
+    assert!(!path.join("0.hint").exists());
+}
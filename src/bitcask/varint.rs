@@ -0,0 +1,54 @@
+use std::io;
+use std::io::Read;
+
+
+/// Appends `value` as an LEB128 varint: seven bits per byte, least
+/// significant group first, high bit set on all but the last byte.
+pub fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+
+pub fn read_varint<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut value = 0u64;
+    let mut shift = 0;
+    loop {
+        let mut byte = [0; 1];
+        try!(reader.read_exact(&mut byte));
+        if shift == 63 && byte[0] > 1 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "varint overflows u64"));
+        }
+        value |= ((byte[0] & 0x7f) as u64) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+    }
+}
+
+
+/// Number of bytes `write_varint` uses for `value`.
+pub fn varint_len(mut value: u64) -> usize {
+    let mut len = 1;
+    while value >= 0x80 {
+        value >>= 7;
+        len += 1;
+    }
+    len
+}
+
+
+#[test]
+fn test_varint_round_trip() {
+    for &value in &[0, 1, 127, 128, 255, 300, 1 << 32, u64::max_value()] {
+        let mut buf = Vec::new();
+        write_varint(&mut buf, value);
+        assert_eq!(buf.len(), varint_len(value));
+        assert_eq!(read_varint(&mut &buf[..]).unwrap(), value);
+    }
+    assert!(read_varint(&mut &[0x80u8][..]).is_err());
+}
@@ -37,5 +37,57 @@ error_chain!{
     // the same as `quick_error!`, but the `from()` and `cause()`
     // syntax is not supported.
     errors {
+        BadMagic(file: String) {
+            description("not a bitcask file")
+            display("{} is not a bitcask file, or predates format version 1 and needs bitcask-upgrade", file)
+        }
+        UnsupportedVersion(file: String, version: u16) {
+            description("unsupported format version")
+            display("{} has unsupported format version {}, older versions need bitcask-upgrade", file, version)
+        }
+        Locked(path: String) {
+            description("directory locked by another process")
+            display("{} is locked by another process", path)
+        }
+        Encryption(msg: String) {
+            description("encryption error")
+            display("encryption error: {}", msg)
+        }
+        Compression(msg: String) {
+            description("corrupt compressed value")
+            display("corrupt compressed value: {}", msg)
+        }
+        BadManifest(file: String) {
+            description("malformed manifest")
+            display("{} is not a valid manifest", file)
+        }
+        ReadOnly {
+            description("opened read-only")
+            display("the store is opened read-only")
+        }
+        TornTail(file: String, bytes: u64) {
+            description("torn record at the end of a file")
+            display("{} ends with {} bytes of incomplete records", file, bytes)
+        }
+        CorruptRecord(file: String, offset: u64) {
+            description("corrupt record")
+            display("{} has a corrupt record at offset {} followed by more records; bitcask-tool repair cuts the file there", file, offset)
+        }
+        KeyTooLarge(size: usize, max: usize) {
+            description("key too large")
+            display("key of {} bytes exceeds the limit of {} bytes", size, max)
+        }
+        ValueTooLarge(size: u64, max: u64) {
+            description("value too large")
+            display("value of {} bytes exceeds the limit of {} bytes", size, max)
+        }
+        MissingFile(file_id: u32) {
+            description("keydir points to a missing data file")
+            display("keydir points to data file {}, which is not open", file_id)
+        }
+        BadExport(record: u64, msg: String) {
+            description("invalid export record")
+            display("invalid export record {}: {}", record, msg)
+        }
     }
 }
\ No newline at end of file
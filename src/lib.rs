@@ -0,0 +1,52 @@
+extern crate base64;
+extern crate byteorder;
+extern crate chacha20poly1305;
+extern crate time;
+extern crate memcached_protocal;
+extern crate nix;
+extern crate serde_json;
+extern crate lz4_flex;
+extern crate snap;
+extern crate zstd;
+#[macro_use]
+extern crate error_chain;
+
+mod bitcask;
+mod error;
+mod protocal;
+mod server;
+
+pub use bitcask::Bitcask;
+pub use bitcask::BitcaskOptions;
+pub use bitcask::Iter;
+pub use bitcask::Snapshot;
+pub use bitcask::compression::Compression;
+pub use bitcask::encryption::Keyring;
+pub use bitcask::export::ExportFormat;
+pub use bitcask::export::ExportRecord;
+pub use bitcask::filter::CompactionFilter;
+pub use bitcask::filter::FilterDecision;
+pub use bitcask::filter::RecordMetadata;
+pub use bitcask::WriteOp;
+pub use bitcask::data_file::DataEntry;
+pub use bitcask::data_file::DataFile;
+pub use bitcask::hint_file::HintEntry;
+pub use bitcask::hint_file::HintFile;
+pub use bitcask::header::FileHeader;
+pub use bitcask::header::FORMAT_VERSION;
+pub use bitcask::inspect::dump;
+pub use bitcask::inspect::escape;
+pub use bitcask::inspect::repair;
+pub use bitcask::inspect::verify;
+pub use bitcask::manifest::Manifest;
+pub use bitcask::merge::MergeStats;
+pub use bitcask::merge::MergeWindow;
+pub use bitcask::recovery::TailRecovery;
+pub use bitcask::stats::FileStats;
+pub use bitcask::upgrade::UpgradeReport;
+pub use bitcask::upgrade::upgrade;
+pub use error::ChainErr;
+pub use error::Error;
+pub use error::ErrorKind;
+pub use error::Result;
+pub use server::Server;
@@ -1,111 +1,154 @@
-extern crate byteorder;
-extern crate core;
-extern crate time;
-extern crate memcached_protocal;
-#[macro_use]
-extern crate error_chain;
-
-mod bitcask;
-mod protocal;
-mod error;
-
-use std::net::TcpListener;
-use std::net::TcpStream;
-use std::thread;
-use std::sync::Arc;
-use std::sync::RwLock;
-
-use memcached_protocal::Delete;
-use memcached_protocal::Store;
-use memcached_protocal::Retrieval;
-use memcached_protocal::RetrievalResponse;
-use memcached_protocal::RetrievalResponseItem;
-use memcached_protocal::DeleteResponse;
-use memcached_protocal::StoreResponse;
-
-use ::protocal::memcached::MemcachedClient;
-use ::error::ErrorKind;
-
-
-fn handle_client(stream: TcpStream, db: Arc<RwLock<bitcask::Bitcask>>) {
-    let mut client = MemcachedClient::new(&stream);
-    loop {
-        let cmd = match client.read() {
-            Ok(cmd) => cmd,
-            Err(e) => {
-                println!("{:?}", e);
-
-                match *e.kind() {
-                    ErrorKind::Protocal(memcached_protocal::ErrorKind::StdIO) => {
-                        return;
-                    },
-                    _ => {
-                        continue;
-                    }
-                }
-            }
-        };
+extern crate bitcask;
+
+use std::env;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use bitcask::Bitcask;
+use bitcask::BitcaskOptions;
+use bitcask::Compression;
+use bitcask::MergeWindow;
+use bitcask::Result;
+use bitcask::Server;
+
+
+/// Where the server listens, taken from the command line:
+///
+/// * `--listen <addr>`: TCP address, `0.0.0.0:12340` by default.
+/// * `--no-tcp`: don't listen on TCP at all.
+/// * `--unix-socket <path>`: also listen on a Unix domain socket.
+/// * `--unix-socket-mode <octal>`: permissions of the socket file, `0660`
+///   by default.
+/// * `--follow`: open the store read-only and keep up with the process
+///   writing to it, to serve reads only.
+/// * `--compression <codec>`: compress new values with `lz4`, `zstd` or
+///   `snappy`, `none` by default.
+/// * `--key-file <path>`: encrypt records with the keys in this file.
+/// * `--auto-merge`: merge fragmented and small files in the background.
+/// * `--merge-window <HH:MM-HH:MM>`: only start merges in this local time
+///   window.
+/// * `--merge-rate <bytes>`: limit merge reads and writes to this many bytes
+///   per second.
+/// * `--backup-dir <path>`: let clients write checkpoints under this
+///   directory, checkpoints are disabled otherwise.
+struct Config {
+    tcp_addr: Option<String>,
+    unix_socket: Option<PathBuf>,
+    unix_socket_mode: u32,
+    follow: bool,
+    compression: Compression,
+    key_file: Option<PathBuf>,
+    auto_merge: bool,
+    merge_window: Option<MergeWindow>,
+    merge_rate: u64,
+    backup_dir: Option<PathBuf>,
+}
 
-        match cmd {
-            Retrieval(ref cmd) => {
-                let mut locked_db = db.write().unwrap();
-                let resp = RetrievalResponse(cmd.keys.iter()
-                    .filter_map(|key| {
-                        locked_db.get(key.clone())
-                            .map(|value| {
-                                RetrievalResponseItem{
-                                    key: key.clone(),
-                                    flags: 0,
-                                    bytes: value.len() as u32,
-                                    cas_unique: None,
-                                    data_block: value.clone(),
-                                }
-                            })
-                    })
-                    .collect::<Vec<RetrievalResponseItem>>()
-                );
-                let _ = client.write(resp);
-            },
-            Delete(ref cmd) => {
-                let mut locked_db = db.write().unwrap();
-                locked_db.delete(cmd.key.clone());
-                let _ = client.write(DeleteResponse::Deleted);
-            },
-            Store(ref cmd) => {
-                if cmd.command_name.as_bytes() == b"set" {
-                    let mut locked_db = db.write().unwrap();
-                    locked_db.put(cmd.key.clone(), cmd.data_block.clone());
-                    let _ = client.write(StoreResponse::Stored);
-                }
+
+impl Config {
+    fn from_args<I: Iterator<Item=String>>(mut args: I) -> Result<Config> {
+        let mut config = Config {
+            tcp_addr: Some("0.0.0.0:12340".to_owned()),
+            unix_socket: None,
+            unix_socket_mode: 0o660,
+            follow: false,
+            compression: Compression::None,
+            key_file: None,
+            auto_merge: false,
+            merge_window: None,
+            merge_rate: 0,
+            backup_dir: None,
+        };
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--listen" => config.tcp_addr = Some(try!(arg_value(&mut args, &arg))),
+                "--no-tcp" => config.tcp_addr = None,
+                "--unix-socket" => config.unix_socket = Some(PathBuf::from(try!(arg_value(&mut args, &arg)))),
+                "--unix-socket-mode" => {
+                    let mode = try!(arg_value(&mut args, &arg));
+                    config.unix_socket_mode = try!(u32::from_str_radix(&mode, 8));
+                },
+                "--follow" => config.follow = true,
+                "--key-file" => config.key_file = Some(PathBuf::from(try!(arg_value(&mut args, &arg)))),
+                "--auto-merge" => config.auto_merge = true,
+                "--merge-window" => config.merge_window = Some(try!(try!(arg_value(&mut args, &arg)).parse())),
+                "--merge-rate" => config.merge_rate = try!(try!(arg_value(&mut args, &arg)).parse()),
+                "--backup-dir" => config.backup_dir = Some(PathBuf::from(try!(arg_value(&mut args, &arg)))),
+                "--compression" => config.compression = try!(try!(arg_value(&mut args, &arg)).parse()),
+                _ => return Err(format!("unknown argument {}", arg).into()),
             }
         }
+        if config.tcp_addr.is_none() && config.unix_socket.is_none() {
+            return Err("nothing to listen on, --no-tcp needs --unix-socket".into());
+        }
+        Ok(config)
     }
 }
 
 
+fn arg_value<I: Iterator<Item=String>>(args: &mut I, name: &str) -> Result<String> {
+    args.next().ok_or_else(|| format!("missing value for {}", name).into())
+}
+
+
 fn main() {
-    let mut db = Arc::new(RwLock::new(bitcask::Bitcask::new("data".to_owned(), bitcask::BitcaskOptions::default())));
-
-    let listener = TcpListener::bind("0.0.0.0:12340").expect("bind error");
-    println!("bind");
-
-    for stream in listener.incoming() {
-        println!("new connection");
-        match stream {
-            Ok(stream) => {
-                let db_clone = db.clone();
-                thread::spawn(move || {
-                    handle_client(stream, db_clone);
-                });
-            }
-            Err(e) => {
-                println!("{:?}", e);
-            }
-        }
+    let config = Config::from_args(env::args().skip(1)).expect("parse arguments");
+    let mut option = BitcaskOptions::default();
+    option.compression = config.compression;
+    option.key_file = config.key_file;
+    option.merge_window = config.merge_window;
+    option.merge_rate_limit = config.merge_rate;
+    let db = if config.follow {
+        Bitcask::open_read_only("data".to_owned(), option)
+    } else {
+        Bitcask::new("data".to_owned(), option)
+    };
+    let mut server = Server::new(db.expect("open bitcask"));
+    if let Some(ref dir) = config.backup_dir {
+        server.set_backup_dir(dir);
     }
-    //    for i in 1..100 {
-    //        let _ = db.put("hello".to_owned(), vec![i]);
-    //    }
-    //    let _ = db.delete("a".to_owned());
-    //    println!("{:?}", db.get("hello".to_owned()));
+    if config.follow {
+        server.follow(Duration::from_secs(1));
+    } else if config.auto_merge {
+        server.auto_merge(Duration::from_secs(60));
+    }
+
+    if let Some(ref path) = config.unix_socket {
+        server.listen_unix(path, config.unix_socket_mode).expect("bind unix socket error");
+        println!("bind {}", path.display());
+    }
+    if let Some(ref addr) = config.tcp_addr {
+        let addr = server.listen_tcp(addr.as_str()).expect("bind error");
+        println!("bind {}", addr);
+    }
+
+    server.join();
+}
+
+
+#[test]
+fn test_config_from_args() {
+    let args = vec!["--no-tcp", "--unix-socket", "/tmp/bitcask.sock", "--unix-socket-mode", "0600"];
+    let config = Config::from_args(args.into_iter().map(|s| s.to_owned())).unwrap();
+    assert_eq!(config.tcp_addr, None);
+    assert_eq!(config.unix_socket, Some(PathBuf::from("/tmp/bitcask.sock")));
+    assert_eq!(config.unix_socket_mode, 0o600);
+    assert!(!config.follow);
+    assert_eq!(config.compression, Compression::None);
+    assert_eq!(config.backup_dir, None);
+
+    let config = Config::from_args(vec!["--backup-dir", "/backup"].into_iter().map(|s| s.to_owned())).unwrap();
+    assert_eq!(config.backup_dir, Some(PathBuf::from("/backup")));
+
+    let config = Config::from_args(vec!["--compression", "lz4"].into_iter().map(|s| s.to_owned())).unwrap();
+    assert_eq!(config.compression, Compression::Lz4);
+    assert!(Config::from_args(vec!["--compression".to_owned(), "gzip".to_owned()].into_iter()).is_err());
+
+    let args = vec!["--auto-merge", "--merge-window", "02:00-05:00", "--merge-rate", "1048576"];
+    let config = Config::from_args(args.into_iter().map(|s| s.to_owned())).unwrap();
+    assert!(config.auto_merge);
+    assert_eq!(config.merge_window, Some(MergeWindow::new(2, 0, 5, 0)));
+    assert_eq!(config.merge_rate, 1048576);
+
+    assert!(Config::from_args(vec!["--no-tcp".to_owned()].into_iter()).is_err());
 }
@@ -0,0 +1,194 @@
+use std::path::Component;
+use std::path::PathBuf;
+
+use memcached_protocal;
+use memcached_protocal::Response;
+
+use ::error::ErrorKind;
+use ::error::Result;
+
+
+/// Commands of ours on top of the memcached protocal, for operating the
+/// store. Each is a single line, like `keys user:`.
+#[derive(Debug, PartialEq)]
+pub enum AdminCommand {
+    /// `keys [<prefix>]`: lists the live keys, only those starting with
+    /// `prefix` if given.
+    Keys(Option<Vec<u8>>),
+    /// `checkpoint <dir>`: writes a copy of the store to `dir` under the
+    /// server's backup directory, see `Bitcask::checkpoint`. `dir` is
+    /// relative and can't climb out with `..`.
+    Checkpoint(PathBuf),
+    /// `merge-stats`: how the running merge is getting on, and totals of
+    /// finished ones, see `Bitcask::merge_stats`.
+    MergeStats,
+    /// `merge-rate <bytes>`: limits merges to `bytes` per second of reads
+    /// and writes, 0 for no limit.
+    MergeRate(u64),
+    /// `merge-pause`: stops the running merge and keeps new ones from
+    /// starting.
+    MergePause,
+    /// `merge-resume`: undoes `merge-pause`.
+    MergeResume,
+}
+
+
+impl AdminCommand {
+    /// Whether the command line `line` names an admin command.
+    pub fn matches(line: &[u8]) -> bool {
+        let name = line.split(|&b| b == b' ' || b == b'\r').next().unwrap_or(b"");
+        match name {
+            b"keys" | b"checkpoint" | b"merge-stats" | b"merge-rate" | b"merge-pause" | b"merge-resume" => true,
+            _ => false,
+        }
+    }
+
+    /// Parses a command line, without its `\r\n`.
+    pub fn parse(line: &[u8]) -> Result<AdminCommand> {
+        let line = try!(String::from_utf8(line.to_vec()));
+        let mut words = line.split_whitespace();
+        let cmd = match words.next() {
+            Some("keys") => AdminCommand::Keys(match words.next() {
+                Some(prefix) => Some(try!(decode_key(prefix))),
+                None => None,
+            }),
+            Some("checkpoint") => match words.next() {
+                Some(dir) => {
+                    let dir = PathBuf::from(dir);
+                    if !dir.components().all(|c| match c { Component::Normal(_) => true, _ => false }) {
+                        return Err(client_error("bad checkpoint path"));
+                    }
+                    AdminCommand::Checkpoint(dir)
+                },
+                None => return Err(client_error("wrong size of params")),
+            },
+            Some("merge-stats") => AdminCommand::MergeStats,
+            Some("merge-rate") => match words.next() {
+                Some(rate) => AdminCommand::MergeRate(try!(rate.parse::<u64>()
+                    .map_err(|_| client_error("bad data chunk")))),
+                None => return Err(client_error("wrong size of params")),
+            },
+            Some("merge-pause") => AdminCommand::MergePause,
+            Some("merge-resume") => AdminCommand::MergeResume,
+            _ => return Err(client_error("not supported command")),
+        };
+        if words.next().is_some() {
+            return Err(client_error("wrong size of params"));
+        }
+        Ok(cmd)
+    }
+}
+
+
+/// Replies to admin commands.
+pub enum AdminResponse {
+    /// Reply to `keys`: a `KEY <key>` line per key, then `END`.
+    Keys(Vec<Vec<u8>>),
+    /// `OK`, the command is done.
+    Ok,
+    /// A `STAT <name> <value>` line per pair, then `END`, like memcached's
+    /// `stats`.
+    Stats(Vec<(String, String)>),
+}
+
+
+impl Response for AdminResponse {
+    fn to_bytes(&self) -> memcached_protocal::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        match *self {
+            AdminResponse::Keys(ref keys) => {
+                for key in keys.iter() {
+                    buf.extend_from_slice(b"KEY ");
+                    buf.extend_from_slice(encode_key(key).as_bytes());
+                    buf.extend_from_slice(b"\r\n");
+                }
+                buf.extend_from_slice(b"END\r\n");
+            },
+            AdminResponse::Ok => buf.extend_from_slice(b"OK\r\n"),
+            AdminResponse::Stats(ref stats) => {
+                for &(ref name, ref value) in stats.iter() {
+                    buf.extend_from_slice(format!("STAT {} {}\r\n", name, value).as_bytes());
+                }
+                buf.extend_from_slice(b"END\r\n");
+            },
+        }
+        Ok(buf)
+    }
+}
+
+
+/// Keys are binary, so bytes that can't appear in a protocal line are
+/// percent-encoded, as memcached does in its own key dumps.
+pub fn encode_key(key: &[u8]) -> String {
+    let mut encoded = String::with_capacity(key.len());
+    for &b in key {
+        if b <= b' ' || b >= 0x7f || b == b'%' {
+            encoded.push_str(&format!("%{:02X}", b));
+        } else {
+            encoded.push(b as char);
+        }
+    }
+    encoded
+}
+
+
+pub fn decode_key(encoded: &str) -> Result<Vec<u8>> {
+    let bytes = encoded.as_bytes();
+    let mut key = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = match encoded.get(i + 1..i + 3) {
+                Some(hex) => hex,
+                None => return Err(client_error("bad percent-encoding")),
+            };
+            key.push(try!(u8::from_str_radix(hex, 16)));
+            i += 3;
+        } else {
+            key.push(bytes[i]);
+            i += 1;
+        }
+    }
+    Ok(key)
+}
+
+
+fn client_error(msg: &str) -> ::error::Error {
+    ErrorKind::Protocal(memcached_protocal::ErrorKind::ClientError(msg.to_owned())).into()
+}
+
+
+#[test]
+fn test_parse() {
+    assert!(AdminCommand::matches(b"keys\r\n"));
+    assert!(AdminCommand::matches(b"keys user:\r\n"));
+    assert!(!AdminCommand::matches(b"keyset\r\n"));
+    assert_eq!(AdminCommand::parse(b"keys").unwrap(), AdminCommand::Keys(None));
+    assert_eq!(AdminCommand::parse(b"keys a%20b").unwrap(), AdminCommand::Keys(Some(b"a b".to_vec())));
+    assert!(AdminCommand::parse(b"keys a b").is_err());
+    assert!(AdminCommand::parse(b"keys a%2").is_err());
+    assert_eq!(AdminCommand::parse(b"checkpoint daily/1").unwrap(), AdminCommand::Checkpoint(PathBuf::from("daily/1")));
+    assert!(AdminCommand::parse(b"checkpoint /backup").is_err());
+    assert!(AdminCommand::parse(b"checkpoint daily/../../etc").is_err());
+    assert!(AdminCommand::parse(b"checkpoint ..").is_err());
+    assert!(AdminCommand::parse(b"checkpoint").is_err());
+    assert!(AdminCommand::matches(b"merge-stats\r\n"));
+    assert_eq!(AdminCommand::parse(b"merge-stats").unwrap(), AdminCommand::MergeStats);
+    assert!(AdminCommand::parse(b"merge-stats now").is_err());
+    assert!(AdminCommand::matches(b"merge-rate 1000\r\n"));
+    assert_eq!(AdminCommand::parse(b"merge-rate 1000").unwrap(), AdminCommand::MergeRate(1000));
+    assert!(AdminCommand::parse(b"merge-rate fast").is_err());
+    assert!(AdminCommand::parse(b"merge-rate").is_err());
+    assert_eq!(AdminCommand::parse(b"merge-pause").unwrap(), AdminCommand::MergePause);
+    assert_eq!(AdminCommand::parse(b"merge-resume").unwrap(), AdminCommand::MergeResume);
+}
+
+#[test]
+fn test_admin_response() {
+    let resp = AdminResponse::Keys(vec![b"a".to_vec(), vec![b'x', b' ', 0xff, b'%']]);
+    assert_eq!(resp.to_bytes().unwrap(), b"KEY a\r\nKEY x%20%FF%25\r\nEND\r\n".to_vec());
+    assert_eq!(AdminResponse::Ok.to_bytes().unwrap(), b"OK\r\n".to_vec());
+    let resp = AdminResponse::Stats(vec![("running".to_owned(), "0".to_owned())]);
+    assert_eq!(resp.to_bytes().unwrap(), b"STAT running 0\r\nEND\r\n".to_vec());
+    assert_eq!(decode_key("x%20%FF%25").unwrap(), vec![b'x', b' ', 0xff, b'%']);
+}
@@ -0,0 +1,218 @@
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Write;
+
+use memcached_protocal;
+use memcached_protocal::Command;
+use memcached_protocal::Response;
+
+use ::error::ErrorKind;
+use ::error::Result;
+use ::protocal::admin::AdminCommand;
+
+
+/// Error replies of the memcached text protocal.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ErrorResponse {
+    /// `ERROR\r\n`, the client sent a command name we don't know.
+    Error,
+    /// `CLIENT_ERROR <msg>\r\n`, the input doesn't conform to the protocal.
+    ClientError(String),
+    /// `SERVER_ERROR <msg>\r\n`, the command was valid but the engine failed.
+    ServerError(String),
+}
+
+
+impl ErrorResponse {
+    /// Maps an error to the reply the client should get. `None` means the
+    /// connection itself is broken and there is nobody left to reply to.
+    pub fn from_error_kind(kind: &ErrorKind) -> Option<ErrorResponse> {
+        use memcached_protocal::ErrorKind as P;
+
+        Some(match *kind {
+            ErrorKind::Protocal(P::StdIO) => return None,
+            ErrorKind::Protocal(P::Error) => ErrorResponse::Error,
+            ErrorKind::Protocal(P::ClientError(ref msg)) => {
+                if msg == "not supported command" {
+                    ErrorResponse::Error
+                } else {
+                    ErrorResponse::ClientError(msg.clone())
+                }
+            },
+            ErrorKind::Protocal(P::ServerError(ref msg)) => ErrorResponse::ServerError(msg.clone()),
+            ErrorKind::Protocal(ref e) => ErrorResponse::ClientError(format!("{}", e)),
+            ErrorKind::FromUtf8Error | ErrorKind::ParseIntError |
+            ErrorKind::KeyTooLarge(..) | ErrorKind::ValueTooLarge(..) => {
+                ErrorResponse::ClientError(format!("{}", kind))
+            },
+            ref e => ErrorResponse::ServerError(format!("{}", e)),
+        })
+    }
+}
+
+
+impl Response for ErrorResponse {
+    fn to_bytes(&self) -> memcached_protocal::Result<Vec<u8>> {
+        use self::ErrorResponse::*;
+
+        // A reply is a single line, so the message must not contain one.
+        let line = |prefix: &str, msg: &str| {
+            format!("{} {}\r\n", prefix, msg.replace(|c| c == '\r' || c == '\n', " ")).into_bytes()
+        };
+        Ok(match *self {
+            Error => b"ERROR\r\n".to_vec(),
+            ClientError(ref msg) => line("CLIENT_ERROR", msg),
+            ServerError(ref msg) => line("SERVER_ERROR", msg),
+        })
+    }
+}
+
+
+/// What a client can send: a memcached command, or one of our own.
+#[derive(Debug)]
+pub enum Request {
+    Command(Command),
+    Admin(AdminCommand),
+}
+
+
+/// Speaks the memcached text protocal over any stream that, like
+/// `TcpStream` and `UnixStream`, can be read and written through a shared
+/// reference.
+pub struct MemcachedClient<'a, S: 'a> {
+    reader: BufReader<&'a S>,
+    writer: &'a S,
+}
+
+
+impl<'a, S> MemcachedClient<'a, S> where &'a S: Read + Write {
+    pub fn new(stream: &'a S) -> MemcachedClient<'a, S> {
+        MemcachedClient {
+            reader: BufReader::new(stream),
+            writer: stream,
+        }
+    }
+
+    /// Reads the next command. On a protocal error the stream is
+    /// resynchronized to the start of the next command line before the error
+    /// is returned, so the caller can reply and keep reading.
+    pub fn read(&mut self) -> Result<Request> {
+        // The buffer may hold only part of the command line, so all of it is
+        // read before telling what command it is.
+        let mut line = try!(self.read_line());
+        if AdminCommand::matches(&line) {
+            while line.last() == Some(&b'\n') || line.last() == Some(&b'\r') {
+                line.pop();
+            }
+            return AdminCommand::parse(&line).map(Request::Admin);
+        }
+        let ret = memcached_protocal::parse(&mut line.as_slice().chain(&mut self.reader));
+        match ret {
+            Ok(cmd) => Ok(Request::Command(cmd)),
+            Err(e) => {
+                let data_block_pending = match *e.kind() {
+                    memcached_protocal::ErrorKind::StdIO => return Err(e.into()),
+                    memcached_protocal::ErrorKind::ClientError(ref msg) => msg != "error data block",
+                    _ => true,
+                };
+                // Whatever the parser left of the command line goes with
+                // `line`. A storage command that failed on its command line
+                // leaves its data block behind, which is skipped.
+                if is_storage_command(&line) && data_block_pending {
+                    try!(self.skip_line());
+                }
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Whether more input is already buffered, i.e. the client pipelined
+    /// another command behind the one just read.
+    pub fn has_buffered(&self) -> bool {
+        !self.reader.buffer().is_empty()
+    }
+
+    pub fn write<R: Response>(&mut self, resp: R) -> Result<()> {
+        let buf = try!(resp.to_bytes());
+        try!(self.writer.write_all(&buf));
+        Ok(())
+    }
+
+    /// Replies with the protocal error matching `kind`. Returns `false` if
+    /// the connection is gone and should be closed.
+    pub fn write_error(&mut self, kind: &ErrorKind) -> bool {
+        match ErrorResponse::from_error_kind(kind) {
+            Some(resp) => self.write(resp).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Reads a command line along with its `\r\n`.
+    fn read_line(&mut self) -> Result<Vec<u8>> {
+        let mut line = Vec::new();
+        loop {
+            let n = try!(self.reader.read_until(b'\n', &mut line).map_err(memcached_protocal::Error::from));
+            if line.ends_with(b"\r\n") {
+                return Ok(line);
+            }
+            if n == 0 {
+                let eof = ::std::io::Error::new(::std::io::ErrorKind::UnexpectedEof, "end of buf");
+                return Err(memcached_protocal::Error::from(eof).into());
+            }
+        }
+    }
+
+    fn skip_line(&mut self) -> Result<()> {
+        let mut line = Vec::new();
+        loop {
+            let n = try!(self.reader.read_until(b'\n', &mut line));
+            if n == 0 || line.ends_with(b"\r\n") {
+                return Ok(());
+            }
+        }
+    }
+}
+
+
+/// Whether the client asked us not to reply, as the trailing `noreply`
+/// argument of storage and deletion commands.
+pub fn is_noreply(noreply: &Option<String>) -> bool {
+    noreply.as_ref().map_or(false, |s| s == "noreply")
+}
+
+
+fn is_storage_command(buf: &[u8]) -> bool {
+    let names: [&[u8]; 6] = [b"set ", b"add ", b"replace ", b"append ", b"prepend ", b"cas "];
+    names.iter().any(|name| buf.starts_with(name))
+}
+
+
+#[test]
+fn test_error_response_bytes() {
+    assert_eq!(ErrorResponse::Error.to_bytes().unwrap(), b"ERROR\r\n".to_vec());
+    assert_eq!(ErrorResponse::ClientError("bad\r\ndata".to_owned()).to_bytes().unwrap(),
+               b"CLIENT_ERROR bad  data\r\n".to_vec());
+    assert_eq!(ErrorResponse::ServerError("disk full".to_owned()).to_bytes().unwrap(),
+               b"SERVER_ERROR disk full\r\n".to_vec());
+}
+
+#[test]
+fn test_is_noreply() {
+    assert!(is_noreply(&Some("noreply".to_owned())));
+    assert!(!is_noreply(&Some("0".to_owned())));
+    assert!(!is_noreply(&None));
+}
+
+#[test]
+fn test_error_kind_mapping() {
+    use memcached_protocal::ErrorKind as P;
+
+    assert_eq!(ErrorResponse::from_error_kind(&ErrorKind::Protocal(P::StdIO)), None);
+    assert_eq!(ErrorResponse::from_error_kind(&ErrorKind::Protocal(P::ClientError("not supported command".to_owned()))),
+               Some(ErrorResponse::Error));
+    assert_eq!(ErrorResponse::from_error_kind(&ErrorKind::Protocal(P::ClientError("wrong size of params".to_owned()))),
+               Some(ErrorResponse::ClientError("wrong size of params".to_owned())));
+    assert_eq!(ErrorResponse::from_error_kind(&ErrorKind::StdIO),
+               Some(ErrorResponse::ServerError("stdio error".to_owned())));
+}
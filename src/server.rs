@@ -0,0 +1,628 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::Read;
+use std::io::Write;
+use std::mem;
+use std::net::Shutdown;
+use std::net::SocketAddr;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::net::ToSocketAddrs;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixListener;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::sync::RwLock;
+use std::sync::RwLockReadGuard;
+use std::sync::RwLockWriteGuard;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use std::time::Instant;
+
+use memcached_protocal::Command;
+use memcached_protocal::Delete;
+use memcached_protocal::Store;
+use memcached_protocal::Retrieval;
+use memcached_protocal::RetrievalResponse;
+use memcached_protocal::RetrievalResponseItem;
+use memcached_protocal::DeleteResponse;
+use memcached_protocal::StoreResponse;
+
+use ::bitcask;
+use ::bitcask::WriteOp;
+use ::error::ErrorKind;
+use ::error::Result;
+use ::protocal::admin::AdminCommand;
+use ::protocal::admin::AdminResponse;
+use ::protocal::memcached::ErrorResponse;
+use ::protocal::memcached::MemcachedClient;
+use ::protocal::memcached::Request;
+use ::protocal::memcached::is_noreply;
+
+
+/// A memcached server on top of a `Bitcask`.
+///
+/// Listeners are added with `listen_tcp` and `listen_unix`, each accepting
+/// connections on its own thread. `stop` closes the listeners and all open
+/// connections; dropping the server stops it too.
+pub struct Server {
+    db: Arc<RwLock<bitcask::Bitcask>>,
+    stopped: Arc<StopSignal>,
+    connections: Arc<Connections>,
+    listeners: Vec<Listener>,
+    /// The threads of `follow` and `auto_merge`.
+    background: Vec<JoinHandle<()>>,
+    /// Where `checkpoint` may write, disabled if `None`.
+    backup_dir: Option<PathBuf>,
+}
+
+
+/// Set once the server is stopped. Background threads wait on it between
+/// runs, so that `stop` wakes them up at once.
+struct StopSignal {
+    stopped: Mutex<bool>,
+    changed: Condvar,
+}
+
+
+enum Wakeup {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+
+struct Listener {
+    wakeup: Wakeup,
+    thread: JoinHandle<()>,
+}
+
+
+/// Open connections, kept so that `stop` can shut them down and wait for
+/// their threads.
+struct Connections {
+    next_id: AtomicUsize,
+    streams: Mutex<HashMap<usize, Box<dyn Stream>>>,
+    threads: Mutex<HashMap<usize, JoinHandle<()>>>,
+}
+
+
+trait Stream: Send {
+    fn shutdown(&self);
+}
+
+
+impl Stream for TcpStream {
+    fn shutdown(&self) {
+        let _ = TcpStream::shutdown(self, Shutdown::Both);
+    }
+}
+
+
+impl Stream for UnixStream {
+    fn shutdown(&self) {
+        let _ = UnixStream::shutdown(self, Shutdown::Both);
+    }
+}
+
+
+impl Server {
+    pub fn new(db: bitcask::Bitcask) -> Server {
+        Server {
+            db: Arc::new(RwLock::new(db)),
+            stopped: Arc::new(StopSignal::new()),
+            connections: Arc::new(Connections {
+                next_id: AtomicUsize::new(0),
+                streams: Mutex::new(HashMap::new()),
+                threads: Mutex::new(HashMap::new()),
+            }),
+            listeners: Vec::new(),
+            background: Vec::new(),
+            backup_dir: None,
+        }
+    }
+
+    /// Lets clients write checkpoints of the store to directories under
+    /// `dir`. Off by default, as any client could fill the disk with them.
+    /// Applies to the listeners started afterwards.
+    pub fn set_backup_dir<P: AsRef<Path>>(&mut self, dir: P) {
+        self.backup_dir = Some(dir.as_ref().to_path_buf());
+    }
+
+    /// Starts accepting TCP connections on `addr` and returns the bound
+    /// address, which tells the actual port when binding port 0.
+    pub fn listen_tcp<A: ToSocketAddrs>(&mut self, addr: A) -> Result<SocketAddr> {
+        let listener = try!(TcpListener::bind(addr));
+        let local_addr = try!(listener.local_addr());
+        let db = self.db.clone();
+        let stopped = self.stopped.clone();
+        let connections = self.connections.clone();
+        let backup_dir = self.backup_dir.clone();
+        let thread = thread::spawn(move || {
+            for stream in listener.incoming() {
+                if stopped.is_set() {
+                    return;
+                }
+                match stream {
+                    Ok(stream) => {
+                        println!("new connection");
+                        spawn_client(stream.try_clone().ok(), stream, &db, &backup_dir, &connections);
+                    }
+                    Err(e) => {
+                        println!("{:?}", e);
+                    }
+                }
+            }
+        });
+        self.listeners.push(Listener {
+            wakeup: Wakeup::Tcp(local_addr),
+            thread: thread,
+        });
+        Ok(local_addr)
+    }
+
+    /// Starts accepting connections on a Unix domain socket at `path`,
+    /// whose file gets the permission bits `mode`.
+    pub fn listen_unix<P: AsRef<Path>>(&mut self, path: P, mode: u32) -> Result<()> {
+        let listener = try!(bind_unix(path.as_ref(), mode));
+        let db = self.db.clone();
+        let stopped = self.stopped.clone();
+        let connections = self.connections.clone();
+        let backup_dir = self.backup_dir.clone();
+        let thread = thread::spawn(move || {
+            for stream in listener.incoming() {
+                if stopped.is_set() {
+                    return;
+                }
+                match stream {
+                    Ok(stream) => {
+                        println!("new unix connection");
+                        spawn_client(stream.try_clone().ok(), stream, &db, &backup_dir, &connections);
+                    }
+                    Err(e) => {
+                        println!("{:?}", e);
+                    }
+                }
+            }
+        });
+        self.listeners.push(Listener {
+            wakeup: Wakeup::Unix(path.as_ref().to_path_buf()),
+            thread: thread,
+        });
+        Ok(())
+    }
+
+    /// Keeps a store opened read-only up to date with its writer by calling
+    /// `Bitcask::refresh` every `interval`, until the server is stopped.
+    pub fn follow(&mut self, interval: Duration) {
+        let db = self.db.clone();
+        let stopped = self.stopped.clone();
+        self.background.push(thread::spawn(move || {
+            while !stopped.wait(interval) {
+                let result = match db.write() {
+                    Ok(mut db) => db.refresh(),
+                    Err(_) => return,
+                };
+                if let Err(e) = result {
+                    println!("refresh error: {:?}", e);
+                }
+            }
+        }));
+    }
+
+    /// Merges the sealed files the merge options pick, checking every
+    /// `interval`, until the server is stopped. The store is only locked to
+    /// start and finish a merge, and briefly whenever it needs a new file.
+    pub fn auto_merge(&mut self, interval: Duration) {
+        let db = self.db.clone();
+        let stopped = self.stopped.clone();
+        self.background.push(thread::spawn(move || {
+            while !stopped.wait(interval) {
+                if let Err(e) = merge_once(&db) {
+                    println!("merge error: {:?}", e);
+                }
+            }
+        }));
+    }
+
+    /// Blocks until the server is stopped from another thread.
+    pub fn join(mut self) {
+        for listener in mem::take(&mut self.listeners) {
+            let _ = listener.thread.join();
+        }
+    }
+
+    /// Stops accepting connections, shuts down the open ones, cancels the
+    /// running merge and waits for the background threads to end.
+    pub fn stop(&mut self) {
+        self.stopped.set();
+        // A merge may be paused, and would otherwise never end.
+        if let Ok(db) = read_db(&self.db) {
+            db.cancel_merge();
+        }
+        for listener in mem::take(&mut self.listeners) {
+            // The accept loop only notices the flag once it gets a
+            // connection, so give it one.
+            match listener.wakeup {
+                Wakeup::Tcp(addr) => {
+                    let _ = TcpStream::connect(addr);
+                },
+                Wakeup::Unix(ref path) => {
+                    let _ = UnixStream::connect(path);
+                    let _ = fs::remove_file(path);
+                },
+            }
+            let _ = listener.thread.join();
+        }
+        if let Ok(streams) = self.connections.streams.lock() {
+            for stream in streams.values() {
+                stream.shutdown();
+            }
+        }
+        let threads = match self.connections.threads.lock() {
+            Ok(mut threads) => mem::take(&mut *threads),
+            Err(_) => HashMap::new(),
+        };
+        for (_, thread) in threads {
+            let _ = thread.join();
+        }
+        for thread in mem::take(&mut self.background) {
+            let _ = thread.join();
+        }
+    }
+}
+
+
+impl StopSignal {
+    fn new() -> StopSignal {
+        StopSignal {
+            stopped: Mutex::new(false),
+            changed: Condvar::new(),
+        }
+    }
+
+    fn set(&self) {
+        if let Ok(mut stopped) = self.stopped.lock() {
+            *stopped = true;
+        }
+        self.changed.notify_all();
+    }
+
+    fn is_set(&self) -> bool {
+        self.stopped.lock().map(|stopped| *stopped).unwrap_or(true)
+    }
+
+    /// Waits for `timeout` or until set, and tells whether it is set.
+    fn wait(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut stopped = match self.stopped.lock() {
+            Ok(stopped) => stopped,
+            Err(_) => return true,
+        };
+        while !*stopped {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            stopped = match self.changed.wait_timeout(stopped, deadline - now) {
+                Ok((stopped, _)) => stopped,
+                Err(_) => return true,
+            };
+        }
+        *stopped
+    }
+}
+
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+
+fn spawn_client<S>(handle: Option<S>, stream: S, db: &Arc<RwLock<bitcask::Bitcask>>, backup_dir: &Option<PathBuf>,
+                   connections: &Arc<Connections>)
+    where S: Stream + 'static, for<'a> &'a S: Read + Write
+{
+    let id = connections.next_id.fetch_add(1, Ordering::SeqCst);
+    if let (Some(handle), Ok(mut streams)) = (handle, connections.streams.lock()) {
+        streams.insert(id, Box::new(handle));
+    }
+    let db = db.clone();
+    let backup_dir = backup_dir.clone();
+    let shared = connections.clone();
+    // Locked until the thread is listed, so that it can't try to unlist
+    // itself before.
+    let mut threads = match connections.threads.lock() {
+        Ok(threads) => threads,
+        Err(_) => return,
+    };
+    threads.insert(id, thread::spawn(move || {
+        handle_client(stream, db, backup_dir);
+        if let Ok(mut streams) = shared.streams.lock() {
+            streams.remove(&id);
+        }
+        if let Ok(mut threads) = shared.threads.lock() {
+            threads.remove(&id);
+        }
+    }));
+}
+
+
+fn handle_client<S>(stream: S, db: Arc<RwLock<bitcask::Bitcask>>, backup_dir: Option<PathBuf>) where for<'a> &'a S: Read + Write {
+    let mut client = MemcachedClient::new(&stream);
+    let mut pending = None;
+    loop {
+        let read = match pending.take() {
+            Some(read) => read,
+            None => client.read(),
+        };
+        let cmd = match read {
+            Ok(Request::Command(cmd)) => cmd,
+            Ok(Request::Admin(cmd)) => {
+                let ret = admin(&db, backup_dir.as_ref().map(|dir| dir.as_path()), cmd).and_then(|resp| client.write(resp));
+                if let Err(e) = ret {
+                    println!("{:?}", e);
+                    if !client.write_error(e.kind()) {
+                        return;
+                    }
+                }
+                continue;
+            },
+            Err(e) => {
+                println!("{:?}", e);
+
+                if client.write_error(e.kind()) {
+                    continue;
+                }
+                return;
+            }
+        };
+
+        let ret = match cmd {
+            Retrieval(ref cmd) => {
+                retrieve(&db, &cmd.keys).and_then(|resp| client.write(resp))
+            },
+            Store(ref cmd) if cmd.command_name.as_bytes() != b"set" => {
+                // Not supported, but a noreply client still expects silence.
+                if is_noreply(&cmd.noreply) {
+                    Ok(())
+                } else {
+                    client.write(ErrorResponse::Error)
+                }
+            },
+            _ => {
+                // Consecutive writes the client already pipelined are applied
+                // together, under one lock and one append.
+                let mut batch = vec![cmd];
+                while client.has_buffered() {
+                    match client.read() {
+                        Ok(Request::Command(cmd)) => {
+                            if is_write(&cmd) {
+                                batch.push(cmd);
+                            } else {
+                                pending = Some(Ok(Request::Command(cmd)));
+                                break;
+                            }
+                        },
+                        Ok(req) => {
+                            pending = Some(Ok(req));
+                            break;
+                        },
+                        Err(e) => {
+                            pending = Some(Err(e));
+                            break;
+                        }
+                    }
+                }
+                write_batch(&db, &mut client, batch)
+            }
+        };
+
+        if let Err(e) = ret {
+            println!("{:?}", e);
+            if !client.write_error(e.kind()) {
+                return;
+            }
+        }
+    }
+}
+
+
+fn is_write(cmd: &Command) -> bool {
+    match *cmd {
+        Delete(_) => true,
+        Store(ref cmd) => cmd.command_name.as_bytes() == b"set",
+        Retrieval(_) => false,
+    }
+}
+
+
+fn write_noreply(cmd: &Command) -> bool {
+    match *cmd {
+        Delete(ref cmd) => is_noreply(&cmd.noreply),
+        Store(ref cmd) => is_noreply(&cmd.noreply),
+        Retrieval(_) => false,
+    }
+}
+
+
+/// Applies a batch of `set` and `delete` commands and replies to each one,
+/// except those sent with `noreply`.
+fn write_batch<'a, S>(db: &RwLock<bitcask::Bitcask>, client: &mut MemcachedClient<'a, S>, batch: Vec<Command>) -> Result<()>
+    where &'a S: Read + Write
+{
+    let mut locked_db = match write_db(db) {
+        Ok(locked_db) => locked_db,
+        Err(e) => {
+            if let Some(resp) = ErrorResponse::from_error_kind(e.kind()) {
+                for _ in batch.iter().filter(|cmd| !write_noreply(cmd)) {
+                    try!(client.write(resp.clone()));
+                }
+            }
+            return Ok(());
+        },
+    };
+
+    // A command over the size limits is refused on its own, the rest of the
+    // batch is still applied.
+    let mut ops = Vec::with_capacity(batch.len());
+    let mut replies = Vec::with_capacity(batch.len());
+    for cmd in batch {
+        let noreply = write_noreply(&cmd);
+        let (is_delete, op) = match cmd {
+            Delete(cmd) => (true, WriteOp::Delete(cmd.key.into_bytes())),
+            Store(cmd) => (false, WriteOp::Put(cmd.key.into_bytes(), cmd.data_block)),
+            Retrieval(_) => unreachable!(),
+        };
+        match locked_db.check_write(&op) {
+            Ok(()) => {
+                ops.push(op);
+                replies.push((noreply, is_delete, None));
+            },
+            Err(e) => replies.push((noreply, is_delete, Some(e))),
+        }
+    }
+
+    let ret = locked_db.write_batch(ops);
+    drop(locked_db);
+    for (noreply, is_delete, rejected) in replies {
+        if noreply {
+            continue;
+        }
+        try!(match rejected.as_ref().or(ret.as_ref().err()) {
+            Some(e) => match ErrorResponse::from_error_kind(e.kind()) {
+                Some(resp) => client.write(resp),
+                None => Ok(()),
+            },
+            None if is_delete => client.write(DeleteResponse::Deleted),
+            None => client.write(StoreResponse::Stored),
+        });
+    }
+    Ok(())
+}
+
+
+fn admin(db: &RwLock<bitcask::Bitcask>, backup_dir: Option<&Path>, cmd: AdminCommand) -> Result<AdminResponse> {
+    match cmd {
+        AdminCommand::Keys(prefix) => {
+            // Collected at once under the lock, so the listing is consistent.
+            let locked_db = try!(read_db(db));
+            let keys = match prefix {
+                Some(prefix) => locked_db.prefix(prefix).keys().collect(),
+                None => locked_db.keys().collect(),
+            };
+            Ok(AdminResponse::Keys(keys))
+        },
+        AdminCommand::Checkpoint(dir) => {
+            let backup_dir = match backup_dir {
+                Some(backup_dir) => backup_dir,
+                None => return Err("checkpoint is disabled, no backup directory".into()),
+            };
+            try!(try!(read_db(db)).checkpoint(backup_dir.join(dir)));
+            Ok(AdminResponse::Ok)
+        },
+        AdminCommand::MergeStats => {
+            let stats = try!(read_db(db)).merge_stats();
+            let files = stats.files.iter().map(|file_id| file_id.to_string()).collect::<Vec<String>>();
+            Ok(AdminResponse::Stats(vec![
+                ("running".to_owned(), (stats.running as u8).to_string()),
+                ("files".to_owned(), if files.is_empty() { "-".to_owned() } else { files.join(",") }),
+                ("bytes_to_read".to_owned(), stats.bytes_to_read.to_string()),
+                ("bytes_read".to_owned(), stats.bytes_read.to_string()),
+                ("bytes_written".to_owned(), stats.bytes_written.to_string()),
+                ("merges".to_owned(), stats.merges.to_string()),
+                ("files_merged".to_owned(), stats.files_merged.to_string()),
+                ("bytes_reclaimed".to_owned(), stats.bytes_reclaimed.to_string()),
+                ("paused".to_owned(), (stats.paused as u8).to_string()),
+                ("rate_limit".to_owned(), stats.rate_limit.to_string()),
+            ]))
+        },
+        // These only touch the throttle, so a merge holding no lock notices
+        // them right away.
+        AdminCommand::MergeRate(rate) => {
+            try!(read_db(db)).set_merge_rate_limit(rate);
+            Ok(AdminResponse::Ok)
+        },
+        AdminCommand::MergePause => {
+            try!(read_db(db)).pause_merge();
+            Ok(AdminResponse::Ok)
+        },
+        AdminCommand::MergeResume => {
+            try!(read_db(db)).resume_merge();
+            Ok(AdminResponse::Ok)
+        },
+    }
+}
+
+
+/// Runs a merge if one is due.
+fn merge_once(db: &RwLock<bitcask::Bitcask>) -> Result<()> {
+    let mut job = match try!(try!(write_db(db)).start_merge(false)) {
+        Some(job) => job,
+        None => return Ok(()),
+    };
+    println!("merging files {:?}", job.inputs());
+    let result = job.run(&mut || write_db(db).and_then(|mut db| db.reserve_merge_file()));
+    let mut locked_db = try!(write_db(db));
+    match result {
+        Ok(()) => locked_db.finish_merge(job),
+        Err(e) => {
+            try!(locked_db.abort_merge(job));
+            Err(e)
+        },
+    }
+}
+
+
+fn retrieve(db: &RwLock<bitcask::Bitcask>, keys: &[String]) -> Result<RetrievalResponse> {
+    let locked_db = try!(read_db(db));
+    let mut items = Vec::with_capacity(keys.len());
+    for key in keys {
+        if let Some(value) = try!(locked_db.get(key.as_bytes())) {
+            items.push(RetrievalResponseItem{
+                key: key.clone(),
+                flags: 0,
+                bytes: value.len() as u32,
+                cas_unique: None,
+                data_block: value,
+            });
+        }
+    }
+    Ok(RetrievalResponse(items))
+}
+
+
+fn read_db<'a>(db: &'a RwLock<bitcask::Bitcask>) -> Result<RwLockReadGuard<'a, bitcask::Bitcask>> {
+    db.read().map_err(|_| ErrorKind::Msg("database lock poisoned".to_owned()).into())
+}
+
+
+/// A poisoned lock means another connection panicked while holding it. The
+/// engine state can't be trusted then, so the request fails instead of
+/// taking this connection down too.
+fn write_db<'a>(db: &'a RwLock<bitcask::Bitcask>) -> Result<RwLockWriteGuard<'a, bitcask::Bitcask>> {
+    db.write().map_err(|_| ErrorKind::Msg("database lock poisoned".to_owned()).into())
+}
+
+
+/// Binds the socket, replacing a stale socket file left by a previous run.
+fn bind_unix(path: &Path, mode: u32) -> Result<UnixListener> {
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(format!("{} exists and is not a socket", path.display()).into());
+        }
+        try!(fs::remove_file(path));
+    }
+    let listener = try!(UnixListener::bind(path));
+    try!(fs::set_permissions(path, fs::Permissions::from_mode(mode)));
+    Ok(listener)
+}
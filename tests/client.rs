@@ -1,20 +1,239 @@
+extern crate bitcask;
+
+use std::env;
+use std::fs;
+use std::net::Shutdown;
 use std::net::TcpStream;
+use std::path::PathBuf;
 use std::io::Write;
 use std::io::Read;
+use std::thread;
+use std::time::Duration;
+
+use bitcask::Bitcask;
+use bitcask::BitcaskOptions;
+use bitcask::Server;
+
+
+/// An empty directory for a test, removed with what is in it once dropped.
+struct TestDir(PathBuf);
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+fn test_dir(name: &str) -> TestDir {
+    let path = env::temp_dir().join(format!("bitcask-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&path);
+    fs::create_dir_all(&path).unwrap();
+    TestDir(path)
+}
+
+fn start_server(dir: &TestDir) -> (Server, TcpStream) {
+    start_server_with(dir, BitcaskOptions::default())
+}
+
+fn start_server_with(dir: &TestDir, option: BitcaskOptions) -> (Server, TcpStream) {
+    let db = Bitcask::new(dir.0.to_string_lossy().into_owned(), option).unwrap();
+    let mut server = Server::new(db);
+    let addr = server.listen_tcp("127.0.0.1:0").unwrap();
+    let client = TcpStream::connect(addr).unwrap();
+    (server, client)
+}
+
+fn request(client: TcpStream, req: &[u8]) -> String {
+    let mut client = client;
+    client.write_all(req).unwrap();
+    client.shutdown(Shutdown::Write).unwrap();
+    let mut s = String::new();
+    client.read_to_string(&mut s).unwrap();
+    s
+}
 
 #[test]
 fn test_get() {
-    let mut client = TcpStream::connect("127.0.0.1:12340").unwrap();
-    client.write_all(b"set a 0 0 1\r\nk\r\n");
-    client.write_all(b"set a 0 0 1\r\nk\r\n");
-    client.write_all(b"set a 0 0 1\r\nk\r\n");
-    client.write_all(b"delete b\r\n");
-    client.write_all(b"delete kkk\r\n");
-    client.write_all(b"gets b\r\n");
-    client.write_all(b"set a 0 0 1\r\nk\r\n");
-
-    client.write_all(b"gets b\r\n");
+    let dir = test_dir("get");
+    let (_server, mut client) = start_server(&dir);
+    client.write_all(b"set a 0 0 1\r\nk\r\n").unwrap();
+    client.write_all(b"set a 0 0 1\r\nk\r\n").unwrap();
+    client.write_all(b"set a 0 0 1\r\nk\r\n").unwrap();
+    client.write_all(b"delete b\r\n").unwrap();
+    client.write_all(b"delete kkk\r\n").unwrap();
+    client.write_all(b"gets b\r\n").unwrap();
+    client.write_all(b"set a 0 0 1\r\nk\r\n").unwrap();
+
+    client.write_all(b"gets a\r\n").unwrap();
+    client.shutdown(Shutdown::Write).unwrap();
+    let mut s = String::new();
+    client.read_to_string(&mut s).unwrap();
+    assert_eq!(s, "STORED\r\nSTORED\r\nSTORED\r\nDELETED\r\nDELETED\r\nEND\r\nSTORED\r\nVALUE a 0 1 k\r\nEND\r\n");
+}
+
+#[test]
+fn test_stop() {
+    let dir = test_dir("stop");
+    let (mut server, mut client) = start_server(&dir);
+    server.stop();
+    let mut s = String::new();
+    client.read_to_string(&mut s).unwrap();
+    assert_eq!(s, "");
+}
+
+#[test]
+fn test_stop_releases_store() {
+    let dir = test_dir("stop-releases-store");
+    let (mut server, client) = start_server(&dir);
+    server.auto_merge(Duration::from_secs(60));
+    let addr = client.peer_addr().unwrap();
+    assert_eq!(request(client, b"set a 0 0 1\r\n1\r\n"), "STORED\r\n");
+    let _idle = TcpStream::connect(addr).unwrap();
+    // Neither the merge thread nor a connection keeps the store open.
+    drop(server);
+    let db = Bitcask::new(dir.0.to_string_lossy().into_owned(), BitcaskOptions::default()).unwrap();
+    assert_eq!(b"1".to_vec(), db.get("a").unwrap().unwrap());
+}
+
+#[test]
+fn test_keys() {
+    let dir = test_dir("keys");
+    let (_server, mut client) = start_server(&dir);
+    client.write_all(b"set user:1 0 0 1\r\na\r\nset user:2 0 0 1\r\nb\r\nset other 0 0 1\r\nc\r\n").unwrap();
+    client.write_all(b"keys user:\r\nkeys a b\r\n").unwrap();
+    client.shutdown(Shutdown::Write).unwrap();
     let mut s = String::new();
-    client.read_to_string(&mut s);
-    println!("{:?}", s);
-}
\ No newline at end of file
+    client.read_to_string(&mut s).unwrap();
+    assert_eq!(s, "STORED\r\nSTORED\r\nSTORED\r\nKEY user:1\r\nKEY user:2\r\nEND\r\nCLIENT_ERROR wrong size of params\r\n");
+}
+
+#[test]
+fn test_checkpoint() {
+    let dir = test_dir("checkpoint");
+    let backup_dir = test_dir("checkpoint-backup");
+    let db = Bitcask::new(dir.0.to_string_lossy().into_owned(), BitcaskOptions::default()).unwrap();
+    let mut server = Server::new(db);
+    server.set_backup_dir(&backup_dir.0);
+    let addr = server.listen_tcp("127.0.0.1:0").unwrap();
+    let client = TcpStream::connect(addr).unwrap();
+    let s = request(client, b"set a 0 0 1\r\nk\r\ncheckpoint daily/1\r\ncheckpoint ../escape\r\ncheckpoint /tmp/escape\r\n");
+    assert_eq!(s, "STORED\r\nOK\r\nCLIENT_ERROR bad checkpoint path\r\nCLIENT_ERROR bad checkpoint path\r\n");
+
+    let dest = backup_dir.0.join("daily").join("1");
+    let backup = Bitcask::new(dest.to_string_lossy().into_owned(), BitcaskOptions::default()).unwrap();
+    assert_eq!(b"k".to_vec(), backup.get("a").unwrap().unwrap());
+}
+
+#[test]
+fn test_checkpoint_disabled() {
+    let dir = test_dir("checkpoint-disabled");
+    let (_server, client) = start_server(&dir);
+    let s = request(client, b"checkpoint backup\r\n");
+    assert!(s.starts_with("SERVER_ERROR "), s);
+    assert!(!PathBuf::from("backup").exists());
+}
+
+#[test]
+fn test_auto_merge() {
+    let mut option = BitcaskOptions::default();
+    option.file_size_limit = 0;
+    let dir = test_dir("auto-merge");
+    let (mut server, client) = start_server_with(&dir, option);
+    let addr = client.peer_addr().unwrap();
+    assert_eq!(request(client, b"set a 0 0 1\r\n1\r\nset a 0 0 1\r\n2\r\nset a 0 0 1\r\n3\r\n"),
+               "STORED\r\nSTORED\r\nSTORED\r\n");
+    server.auto_merge(Duration::from_millis(10));
+
+    let mut stats = String::new();
+    for _ in 0..500 {
+        stats = request(TcpStream::connect(addr).unwrap(), b"merge-stats\r\n");
+        if stats.contains("STAT merges 1\r\n") {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(stats.starts_with("STAT running 0\r\nSTAT files -\r\n"), stats);
+    assert!(stats.contains("STAT merges 1\r\nSTAT files_merged 1\r\nSTAT bytes_reclaimed 26\r\n"), stats);
+    assert_eq!(request(TcpStream::connect(addr).unwrap(), b"get a\r\n"), "VALUE a 0 1 3\r\nEND\r\n");
+}
+
+#[test]
+fn test_merge_throttle() {
+    let mut option = BitcaskOptions::default();
+    option.file_size_limit = 0;
+    let dir = test_dir("merge-throttle");
+    let (mut server, client) = start_server_with(&dir, option);
+    let addr = client.peer_addr().unwrap();
+    assert_eq!(request(client, b"set a 0 0 1\r\n1\r\nset a 0 0 1\r\n2\r\nmerge-pause\r\nmerge-rate 1000\r\n"),
+               "STORED\r\nSTORED\r\nOK\r\nOK\r\n");
+    server.auto_merge(Duration::from_millis(10));
+    thread::sleep(Duration::from_millis(100));
+    let stats = request(TcpStream::connect(addr).unwrap(), b"merge-stats\r\n");
+    assert!(stats.contains("STAT merges 0\r\n"), stats);
+    assert!(stats.ends_with("STAT paused 1\r\nSTAT rate_limit 1000\r\nEND\r\n"), stats);
+
+    assert_eq!(request(TcpStream::connect(addr).unwrap(), b"merge-resume\r\n"), "OK\r\n");
+    let mut stats = String::new();
+    for _ in 0..500 {
+        stats = request(TcpStream::connect(addr).unwrap(), b"merge-stats\r\n");
+        if stats.contains("STAT merges 1\r\n") {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(stats.contains("STAT merges 1\r\n"), stats);
+    assert!(stats.contains("STAT paused 0\r\n"), stats);
+}
+
+#[test]
+fn test_bad_storage_command() {
+    let dir = test_dir("bad-storage-command");
+    let (_server, client) = start_server(&dir);
+    // Rejected once the command line is read, and before it is even
+    // consumed: the data block is skipped either way.
+    let response = request(client, b"set a 0 0 x 1\r\nk\r\nset \xff 0 0 1\r\nk\r\nset b 0 0 1\r\nv\r\nget b\r\n");
+    let lines = response.split("\r\n").collect::<Vec<_>>();
+    assert_eq!(6, lines.len());
+    assert_eq!("CLIENT_ERROR parse int error", lines[0]);
+    assert!(lines[1].starts_with("CLIENT_ERROR "));
+    assert_eq!(["STORED", "VALUE b 0 1 v", "END", ""], lines[2..6]);
+}
+
+#[test]
+fn test_pipelined_oversize_write() {
+    let dir = test_dir("pipelined-oversize-write");
+    let mut option = BitcaskOptions::default();
+    option.max_value_size = 4;
+    let (_server, client) = start_server_with(&dir, option);
+    // Sent at once, so the three sets land in one batch.
+    let response = request(client, b"set a 0 0 1\r\nx\r\nset b 0 0 5\r\nyyyyy\r\nset c 0 0 1\r\nz\r\nget a b c\r\n");
+    let lines = response.split("\r\n").collect::<Vec<_>>();
+    assert_eq!(7, lines.len());
+    assert_eq!("STORED", lines[0]);
+    assert!(lines[1].starts_with("CLIENT_ERROR "), lines[1].to_owned());
+    assert_eq!(["STORED", "VALUE a 0 1 x", "VALUE c 0 1 z", "END", ""], lines[2..7]);
+}
+
+#[test]
+fn test_noreply() {
+    let dir = test_dir("noreply");
+    let (_server, client) = start_server(&dir);
+    let response = request(client, b"set a 0 0 1 noreply\r\nx\r\nadd b 0 0 1 noreply\r\ny\r\n\
+                                      delete a noreply\r\nset c 0 0 1\r\nz\r\nget a c\r\n");
+    assert_eq!("STORED\r\nVALUE c 0 1 z\r\nEND\r\n", response);
+}
+
+#[test]
+fn test_split_command_line() {
+    let dir = test_dir("split-command-line");
+    let (_server, mut client) = start_server(&dir);
+    // Each command line arrives in two reads.
+    for part in &[&b"set a 0 0 1\r\nk\r\nmerge-"[..], b"stats\r\nget"] {
+        client.write_all(part).unwrap();
+        client.flush().unwrap();
+        thread::sleep(Duration::from_millis(50));
+    }
+    let s = request(client, b" a\r\n");
+    assert!(s.starts_with("STORED\r\nSTAT running 0\r\n"), s);
+    assert!(s.ends_with("END\r\nVALUE a 0 1 k\r\nEND\r\n"), s);
+}